@@ -0,0 +1,79 @@
+//! USB DFU 1.1 definitions shared by the runtime interface of the firmware
+//! and the DFU mode interface of the bootloader.
+
+pub const CLASS: u8 = 0xFE;
+pub const SUBCLASS: u8 = 0x01;
+pub const PROTOCOL_RUNTIME: u8 = 0x01;
+pub const PROTOCOL_DFU: u8 = 0x02;
+
+pub const DESCRIPTOR_FUNCTIONAL: u8 = 0x21;
+
+/// Most bytes per DNLOAD request, one flash page.
+pub const TRANSFER_SIZE: u16 = crate::PAGE_SIZE as u16;
+/// Longest time between DETACH and the reset, in ms.
+pub const DETACH_TIMEOUT: u16 = 1000;
+
+/// bitCanDnload and bitWillDetach: the device resets by itself after
+/// DETACH and after manifestation, it is not manifestation tolerant.
+const ATTRIBUTES: u8 = 0x01 | 0x08;
+
+/// The functional descriptor after its length and type.
+pub const FUNCTIONAL_DESCRIPTOR: [u8; 7] = [
+    ATTRIBUTES,
+    DETACH_TIMEOUT as u8,
+    (DETACH_TIMEOUT >> 8) as u8,
+    TRANSFER_SIZE as u8,
+    (TRANSFER_SIZE >> 8) as u8,
+    // bcdDFUVersion 1.1
+    0x10,
+    0x01,
+];
+
+pub mod request {
+    pub const DETACH: u8 = 0;
+    pub const DNLOAD: u8 = 1;
+    pub const UPLOAD: u8 = 2;
+    pub const GETSTATUS: u8 = 3;
+    pub const CLRSTATUS: u8 = 4;
+    pub const GETSTATE: u8 = 5;
+    pub const ABORT: u8 = 6;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DownloadSync = 3,
+    DownloadBusy = 4,
+    DownloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+/// The status codes used here, out of those the specification defines.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0x00,
+    /// The file is not meant for this device.
+    File = 0x02,
+    Write = 0x03,
+    Erase = 0x04,
+    Prog = 0x06,
+    /// The firmware does not pass its checks.
+    Verify = 0x07,
+    /// The download does not fit the firmware region.
+    Address = 0x08,
+    StalledPacket = 0x0F,
+}
+
+/// The reply to GETSTATUS, without a poll timeout: requests are handled
+/// before they are acknowledged.
+pub fn status_reply(status: Status, state: State) -> [u8; 6] {
+    [status as u8, 0, 0, 0, state as u8, 0]
+}
@@ -0,0 +1,458 @@
+//! Flash layout and boot state shared by the bootloader and the firmware.
+//!
+//! | Offset  | Size  | Content                               |
+//! | ------- | ----- | ------------------------------------- |
+//! | 0x0000  | 23K   | Bootloader                            |
+//! | 0x5C00  | 1K    | Boot state                            |
+//! | 0x6000  | 100K  | Firmware                              |
+//! | 0x1F000 | 4K    | Firmware storage                      |
+//!
+//! There is no room for a second firmware slot, so an update overwrites the
+//! firmware in place. A firmware that fails its checks or does not confirm
+//! itself is not started again, the bootloader stays in DFU mode instead so
+//! another image can be downloaded.
+//!
+//! Offsets are relative to the start of flash, as taken by the flash driver.
+
+#![no_std]
+
+pub mod dfu;
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+pub const FLASH_BASE: u32 = 0x0800_0000;
+pub const PAGE_SIZE: u32 = 1024;
+
+pub const STATE_OFFSET: u32 = 0x5C00;
+pub const APP_OFFSET: u32 = 0x6000;
+pub const APP_SIZE: u32 = 100 * 1024;
+
+/// A word at the top of RAM, outside the memory of both programs, that
+/// survives a reset. The firmware writes [`DFU_REQUEST`] there before
+/// resetting to make the bootloader enter DFU mode.
+pub const DFU_REQUEST_ADDRESS: usize = 0x2000_4FFC;
+pub const DFU_REQUEST: u32 = 0xDF0B_007E;
+
+/// Boots of an unconfirmed firmware before the bootloader gives up on it.
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Ends every downloaded image: the magic, then the size and CRC-32 of the
+/// firmware before the trailer, all little-endian.
+pub const TRAILER_MAGIC: [u8; 4] = *b"BNNA";
+pub const TRAILER_LEN: u32 = 12;
+
+/// An erased word, the state page is only erased when a download starts.
+const ERASED: u32 = 0xFFFF_FFFF;
+
+const SIZE: u32 = 0;
+const CRC: u32 = 4;
+const CONFIRMED: u32 = 8;
+const DOWNLOAD: u32 = 12;
+const ATTEMPTS: u32 = 16;
+const VERIFIED: u32 = ATTEMPTS + 4 * MAX_ATTEMPTS;
+
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// The boot state page. Every field is a word that starts erased and is
+/// written once, the page is erased when a download starts. Only a page that
+/// was never written belongs to firmware flashed with a debug probe, once a
+/// download is recorded the firmware has to match it.
+///
+/// | Offset | Content                                               |
+/// | ------ | ----------------------------------------------------- |
+/// | 0      | Firmware size                                         |
+/// | 4      | Firmware CRC-32                                       |
+/// | 8      | Zero once the firmware confirmed itself               |
+/// | 12     | Zero once a download started                          |
+/// | 16     | One word per boot attempt, zeroed before each attempt |
+/// | 28     | Zero once the download was verified, written last     |
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct State {
+    size: u32,
+    crc: u32,
+    confirmed: bool,
+    download: bool,
+    attempts: u32,
+    verified: bool,
+}
+
+impl State {
+    fn read<F: ReadNorFlash>(flash: &mut F) -> Result<Self, F::Error> {
+        let mut words = [0u8; (VERIFIED + 4) as usize];
+        flash.read(STATE_OFFSET, &mut words)?;
+        let word = |offset: u32| {
+            let offset = offset as usize;
+            u32::from_le_bytes([
+                words[offset],
+                words[offset + 1],
+                words[offset + 2],
+                words[offset + 3],
+            ])
+        };
+        Ok(Self {
+            size: word(SIZE),
+            crc: word(CRC),
+            confirmed: word(CONFIRMED) != ERASED,
+            download: word(DOWNLOAD) != ERASED,
+            attempts: (0..MAX_ATTEMPTS)
+                .take_while(|i| word(ATTEMPTS + 4 * i) != ERASED)
+                .count() as u32,
+            verified: word(VERIFIED) != ERASED,
+        })
+    }
+
+    /// Whether the page was never written, no download ever touched it.
+    fn blank(&self) -> bool {
+        *self
+            == Self {
+                size: ERASED,
+                crc: ERASED,
+                confirmed: false,
+                download: false,
+                attempts: 0,
+                verified: false,
+            }
+    }
+}
+
+/// What the bootloader should do.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Boot {
+    Firmware,
+    Dfu(Reason),
+}
+
+/// Why the firmware is not started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Reason {
+    /// No complete download and no firmware flashed by a probe.
+    NoFirmware,
+    /// The firmware does not match the size and CRC of its download.
+    Corrupt,
+    /// The firmware was started [`MAX_ATTEMPTS`] times without confirming.
+    NotConfirmed,
+}
+
+/// Checks the firmware and counts the boot attempt if it is started
+/// unconfirmed.
+pub fn boot<F: NorFlash>(flash: &mut F) -> Result<Boot, F::Error> {
+    let state = State::read(flash)?;
+    if state.blank() {
+        // Flashed by a probe, or nothing at all.
+        if vector_table_valid(flash)? {
+            return Ok(Boot::Firmware);
+        }
+        return Ok(Boot::Dfu(Reason::NoFirmware));
+    }
+    if !state.verified || state.size > APP_SIZE {
+        return Ok(Boot::Dfu(Reason::NoFirmware));
+    }
+    if crc32(flash, APP_OFFSET, state.size)? != state.crc {
+        return Ok(Boot::Dfu(Reason::Corrupt));
+    }
+    if state.confirmed {
+        return Ok(Boot::Firmware);
+    }
+    if state.attempts >= MAX_ATTEMPTS {
+        return Ok(Boot::Dfu(Reason::NotConfirmed));
+    }
+    flash.write(STATE_OFFSET + ATTEMPTS + 4 * state.attempts, &[0; 4])?;
+    Ok(Boot::Firmware)
+}
+
+/// Marks the running firmware as good, so it is started on every boot.
+pub fn confirm<F: NorFlash>(flash: &mut F) -> Result<(), F::Error> {
+    let state = State::read(flash)?;
+    if state.verified && !state.confirmed {
+        flash.write(STATE_OFFSET + CONFIRMED, &[0; 4])?;
+    }
+    Ok(())
+}
+
+/// Invalidates the firmware before a download overwrites it.
+pub fn begin_download<F: NorFlash>(flash: &mut F) -> Result<(), F::Error> {
+    flash.erase(STATE_OFFSET, STATE_OFFSET + PAGE_SIZE)?;
+    flash.write(STATE_OFFSET + DOWNLOAD, &[0; 4])
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DownloadError<E> {
+    Flash(E),
+    /// The image has no trailer, or its size does not match the download.
+    Size,
+    /// The firmware does not match the CRC in the trailer.
+    Crc,
+}
+
+impl<E> From<E> for DownloadError<E> {
+    fn from(error: E) -> Self {
+        DownloadError::Flash(error)
+    }
+}
+
+/// Checks the `len` bytes written at [`APP_OFFSET`] against their trailer
+/// and records the firmware if they match.
+pub fn finish_download<F: NorFlash>(
+    flash: &mut F,
+    len: u32,
+) -> Result<(), DownloadError<F::Error>> {
+    if !(TRAILER_LEN..=APP_SIZE).contains(&len) {
+        return Err(DownloadError::Size);
+    }
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    flash.read(APP_OFFSET + len - TRAILER_LEN, &mut trailer)?;
+    let (magic, rest) = trailer.split_at(4);
+    let (size, crc) = rest.split_at(4);
+    let size = u32::from_le_bytes(size.try_into().unwrap());
+    let crc = u32::from_le_bytes(crc.try_into().unwrap());
+    if magic != TRAILER_MAGIC || size == 0 || size != len - TRAILER_LEN {
+        return Err(DownloadError::Size);
+    }
+    if crc32(flash, APP_OFFSET, size)? != crc {
+        return Err(DownloadError::Crc);
+    }
+    // The flag goes last, it is what makes the record valid.
+    flash.write(STATE_OFFSET + CRC, &crc.to_le_bytes())?;
+    flash.write(STATE_OFFSET + SIZE, &size.to_le_bytes())?;
+    flash.write(STATE_OFFSET + VERIFIED, &[0; 4])?;
+    Ok(())
+}
+
+fn crc32<F: ReadNorFlash>(flash: &mut F, offset: u32, len: u32) -> Result<u32, F::Error> {
+    let mut digest = CRC32.digest();
+    let mut chunk = [0u8; 64];
+    let mut position = 0;
+    while position < len {
+        let n = (len - position).min(chunk.len() as u32);
+        flash.read(offset + position, &mut chunk[..n as usize])?;
+        digest.update(&chunk[..n as usize]);
+        position += n;
+    }
+    Ok(digest.finalize())
+}
+
+/// Whether the firmware starts with a plausible stack pointer and reset
+/// vector.
+fn vector_table_valid<F: ReadNorFlash>(flash: &mut F) -> Result<bool, F::Error> {
+    let mut vectors = [0u8; 8];
+    flash.read(APP_OFFSET, &mut vectors)?;
+    let stack = u32::from_le_bytes([vectors[0], vectors[1], vectors[2], vectors[3]]);
+    let reset = u32::from_le_bytes([vectors[4], vectors[5], vectors[6], vectors[7]]);
+    let app = FLASH_BASE + APP_OFFSET..FLASH_BASE + APP_OFFSET + APP_SIZE;
+    Ok((0x2000_0000..=0x2000_5000).contains(&stack) && app.contains(&(reset & !1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::NorFlashErrorKind;
+    use embedded_storage::nor_flash::{check_erase, check_read, check_write, ErrorType};
+
+    use super::*;
+
+    const FLASH_SIZE: usize = 128 * 1024;
+    const FIRMWARE_LEN: usize = 300;
+    const IMAGE_LEN: usize = FIRMWARE_LEN + TRAILER_LEN as usize;
+
+    /// NOR flash in RAM: writes can only clear bits, erases set whole pages.
+    struct Flash {
+        bytes: [u8; FLASH_SIZE],
+        writes: usize,
+    }
+
+    impl Flash {
+        fn new() -> Self {
+            Self {
+                bytes: [0xFF; FLASH_SIZE],
+                writes: 0,
+            }
+        }
+
+        /// Programs `bytes` at [`APP_OFFSET`] the way a debug probe does,
+        /// without touching the boot state.
+        fn probe(&mut self, bytes: &[u8]) {
+            let app = APP_OFFSET as usize;
+            self.bytes[app..app + APP_SIZE as usize].fill(0xFF);
+            self.bytes[app..app + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    impl ErrorType for Flash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for Flash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            check_read(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            FLASH_SIZE
+        }
+    }
+
+    impl NorFlash for Flash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = PAGE_SIZE as usize;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            check_erase(self, from, to)?;
+            self.bytes[from as usize..to as usize].fill(0xFF);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            check_write(self, offset, bytes.len())?;
+            let offset = offset as usize;
+            for (cell, byte) in self.bytes[offset..].iter_mut().zip(bytes) {
+                *cell &= byte;
+            }
+            self.writes += 1;
+            Ok(())
+        }
+    }
+
+    /// A firmware with a valid vector table, followed by its trailer.
+    fn image() -> [u8; IMAGE_LEN] {
+        let mut image = [0u8; IMAGE_LEN];
+        image[..4].copy_from_slice(&0x2000_5000u32.to_le_bytes());
+        image[4..8].copy_from_slice(&(FLASH_BASE + APP_OFFSET + 0x101).to_le_bytes());
+        for (i, byte) in image[8..FIRMWARE_LEN].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let crc = CRC32.checksum(&image[..FIRMWARE_LEN]);
+        let trailer = &mut image[FIRMWARE_LEN..];
+        trailer[..4].copy_from_slice(&TRAILER_MAGIC);
+        trailer[4..8].copy_from_slice(&(FIRMWARE_LEN as u32).to_le_bytes());
+        trailer[8..].copy_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    fn download(flash: &mut Flash, image: &[u8]) -> Result<(), DownloadError<NorFlashErrorKind>> {
+        begin_download(flash)?;
+        flash.erase(APP_OFFSET, APP_OFFSET + PAGE_SIZE)?;
+        flash.write(APP_OFFSET, image)?;
+        finish_download(flash, image.len() as u32)
+    }
+
+    #[test]
+    fn erased() {
+        let mut flash = Flash::new();
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NoFirmware)));
+    }
+
+    #[test]
+    fn probe_flashed() {
+        let mut flash = Flash::new();
+        flash.probe(&image()[..FIRMWARE_LEN]);
+        for _ in 0..=MAX_ATTEMPTS {
+            assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        }
+        assert_eq!(flash.writes, 0);
+    }
+
+    #[test]
+    fn probe_flashed_after_download() {
+        let mut flash = Flash::new();
+        download(&mut flash, &image()).unwrap();
+        let mut other = image();
+        other[8] ^= 1;
+        flash.probe(&other[..FIRMWARE_LEN]);
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::Corrupt)));
+        // Until the boot state is erased.
+        flash.erase(STATE_OFFSET, STATE_OFFSET + PAGE_SIZE).unwrap();
+        for _ in 0..=MAX_ATTEMPTS {
+            assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        }
+    }
+
+    #[test]
+    fn interrupted_download() {
+        let mut flash = Flash::new();
+        flash.probe(&image()[..FIRMWARE_LEN]);
+        begin_download(&mut flash).unwrap();
+        flash.erase(APP_OFFSET, APP_OFFSET + PAGE_SIZE).unwrap();
+        flash.write(APP_OFFSET, &image()[..64]).unwrap();
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NoFirmware)));
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut flash = Flash::new();
+        download(&mut flash, &image()).unwrap();
+        // Moves the reset vector out of the firmware.
+        flash.bytes[APP_OFFSET as usize + 5] = 0;
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::Corrupt)));
+    }
+
+    #[test]
+    fn corrupt_body() {
+        let mut flash = Flash::new();
+        download(&mut flash, &image()).unwrap();
+        assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        confirm(&mut flash).unwrap();
+        // The vector table is still valid.
+        flash.bytes[APP_OFFSET as usize + FIRMWARE_LEN / 2] ^= 1;
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::Corrupt)));
+    }
+
+    #[test]
+    fn unverified() {
+        let mut flash = Flash::new();
+        let image = image();
+        begin_download(&mut flash).unwrap();
+        flash.write(APP_OFFSET, &image).unwrap();
+        // Power lost before the flag was written.
+        let crc = CRC32.checksum(&image[..FIRMWARE_LEN]);
+        flash.write(STATE_OFFSET + CRC, &crc.to_le_bytes()).unwrap();
+        let size = FIRMWARE_LEN as u32;
+        flash
+            .write(STATE_OFFSET + SIZE, &size.to_le_bytes())
+            .unwrap();
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NoFirmware)));
+    }
+
+    #[test]
+    fn rejected_downloads() {
+        let mut flash = Flash::new();
+        let mut image = image();
+        image[20] ^= 1;
+        assert_eq!(download(&mut flash, &image), Err(DownloadError::Crc));
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NoFirmware)));
+        assert_eq!(
+            download(&mut flash, &image[..FIRMWARE_LEN]),
+            Err(DownloadError::Size)
+        );
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NoFirmware)));
+    }
+
+    #[test]
+    fn not_confirmed() {
+        let mut flash = Flash::new();
+        download(&mut flash, &image()).unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        }
+        assert_eq!(boot(&mut flash), Ok(Boot::Dfu(Reason::NotConfirmed)));
+    }
+
+    #[test]
+    fn confirmed() {
+        let mut flash = Flash::new();
+        download(&mut flash, &image()).unwrap();
+        assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        let writes = flash.writes;
+        confirm(&mut flash).unwrap();
+        assert_eq!(flash.writes, writes + 1);
+        confirm(&mut flash).unwrap();
+        assert_eq!(flash.writes, writes + 1);
+        for _ in 0..=MAX_ATTEMPTS {
+            assert_eq!(boot(&mut flash), Ok(Boot::Firmware));
+        }
+        assert_eq!(flash.writes, writes + 1);
+    }
+}
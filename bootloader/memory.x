@@ -0,0 +1,8 @@
+/* Linker script for the bootloader, see the banana-boot crate for the layout */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 23K
+  BOOT_STATE : ORIGIN = 0x08005C00, LENGTH = 1K
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K - 4
+  BOOT_REQUEST : ORIGIN = 0x20004FFC, LENGTH = 4
+}
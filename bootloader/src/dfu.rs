@@ -0,0 +1,184 @@
+//! The DFU mode interface, writes the downloaded firmware in place.
+//!
+//! Every block is erased and programmed before its DNLOAD request is
+//! acknowledged, so GETSTATUS never reports a busy state. The empty DNLOAD
+//! that ends the download checks the image against its trailer, and the
+//! device resets into the new firmware once the host has read the status.
+
+use banana_boot::{
+    dfu::{self, request, State, Status},
+    DownloadError, APP_OFFSET, APP_SIZE, PAGE_SIZE,
+};
+use embassy_stm32::flash::{Blocking, Flash};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_usb::{
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::Driver,
+    msos, Builder, Handler,
+};
+
+pub(crate) use banana_boot::dfu::TRANSFER_SIZE;
+
+const DEVICE_INTERFACE_GUIDS: &[&str] = &["{a3c1f4d2-8e57-4b19-b0d6-7f2e9c4a61e8}"];
+
+/// Signaled once a downloaded firmware is verified and recorded.
+pub(crate) static MANIFESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub(crate) struct Dfu {
+    flash: Flash<'static, Blocking>,
+    interface: u16,
+    state: State,
+    status: Status,
+    /// Bytes downloaded so far, from [`APP_OFFSET`].
+    len: u32,
+}
+
+impl Dfu {
+    pub(crate) fn new(flash: Flash<'static, Blocking>) -> Self {
+        Self {
+            flash,
+            interface: 0,
+            state: State::DfuIdle,
+            status: Status::Ok,
+            len: 0,
+        }
+    }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    /// Writes the next block of the image.
+    fn download(&mut self, block: &[u8]) -> Result<(), Status> {
+        if self.state == State::DfuIdle {
+            banana_boot::begin_download(&mut self.flash).map_err(|_| Status::Erase)?;
+            self.len = 0;
+        }
+        let start = APP_OFFSET + self.len;
+        let end = start + block.len() as u32;
+        if end > APP_OFFSET + APP_SIZE {
+            return Err(Status::Address);
+        }
+        // Pages are erased by the block they start in.
+        let first_page = start.next_multiple_of(PAGE_SIZE);
+        if first_page < end {
+            self.flash
+                .blocking_erase(first_page, end.next_multiple_of(PAGE_SIZE))
+                .map_err(|_| Status::Erase)?;
+        }
+        // Flash is written in half-words, only the last block may be odd.
+        let (even, odd) = block.split_at(block.len() & !1);
+        self.flash
+            .blocking_write(start, even)
+            .map_err(|_| Status::Prog)?;
+        if let [last] = odd {
+            self.flash
+                .blocking_write(start + even.len() as u32, &[*last, 0xFF])
+                .map_err(|_| Status::Prog)?;
+        }
+        self.len += block.len() as u32;
+        Ok(())
+    }
+
+    fn manifest(&mut self) {
+        match banana_boot::finish_download(&mut self.flash, self.len) {
+            Ok(()) => {
+                self.state = State::ManifestWaitReset;
+                MANIFESTED.signal(());
+            }
+            Err(DownloadError::Flash(_)) => self.fail(Status::Write),
+            Err(DownloadError::Size) => self.fail(Status::File),
+            Err(DownloadError::Crc) => self.fail(Status::Verify),
+        }
+    }
+}
+
+impl Handler for Dfu {
+    fn reset(&mut self) {
+        // An interrupted download leaves the firmware invalid, the next one
+        // starts over.
+        if self.state != State::ManifestWaitReset {
+            self.state = State::DfuIdle;
+            self.status = Status::Ok;
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != self.interface
+        {
+            return None;
+        }
+        match (req.request, self.state) {
+            (request::DNLOAD, State::DfuIdle | State::DownloadIdle) if !data.is_empty() => {
+                if let Err(status) = self.download(data) {
+                    self.fail(status);
+                    return Some(OutResponse::Rejected);
+                }
+                self.state = State::DownloadSync;
+            }
+            (request::DNLOAD, State::DownloadIdle) => self.state = State::ManifestSync,
+            (request::CLRSTATUS, State::Error)
+            | (request::ABORT, State::DfuIdle | State::DownloadIdle) => {
+                self.state = State::DfuIdle;
+                self.status = Status::Ok;
+            }
+            _ => {
+                self.fail(Status::StalledPacket);
+                return Some(OutResponse::Rejected);
+            }
+        }
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != self.interface
+        {
+            return None;
+        }
+        let (status, state);
+        let reply: &[u8] = match req.request {
+            request::GETSTATUS => {
+                match self.state {
+                    State::DownloadSync => self.state = State::DownloadIdle,
+                    State::ManifestSync => self.manifest(),
+                    _ => {}
+                }
+                status = dfu::status_reply(self.status, self.state);
+                &status
+            }
+            request::GETSTATE => {
+                state = [self.state as u8];
+                &state
+            }
+            _ => {
+                self.fail(Status::StalledPacket);
+                return Some(InResponse::Rejected);
+            }
+        };
+        let len = reply.len().min(req.length as usize).min(buf.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// Adds the DFU mode interface.
+pub(crate) fn new<'d, D: Driver<'d>>(builder: &mut Builder<'d, D>, handler: &'d mut Dfu) {
+    {
+        let mut function = builder.function(dfu::CLASS, dfu::SUBCLASS, dfu::PROTOCOL_DFU);
+        function.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        function.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
+        ));
+        let mut interface = function.interface();
+        handler.interface = u8::from(interface.interface_number()) as u16;
+        let mut alt = interface.alt_setting(dfu::CLASS, dfu::SUBCLASS, dfu::PROTOCOL_DFU, None);
+        alt.descriptor(dfu::DESCRIPTOR_FUNCTIONAL, &dfu::FUNCTIONAL_DESCRIPTOR);
+    }
+    builder.handler(handler);
+}
@@ -0,0 +1,138 @@
+//! Starts the firmware, or stays in USB DFU mode to download a new one.
+//!
+//! DFU mode is entered when the firmware asks for it before resetting, or
+//! when [`banana_boot::boot`] finds no firmware fit to start.
+
+#![no_std]
+#![no_main]
+
+mod dfu;
+
+use banana_boot::{Boot, APP_OFFSET, DFU_REQUEST, DFU_REQUEST_ADDRESS, FLASH_BASE};
+use core::{future::poll_fn, task::Poll};
+
+use cortex_m::peripheral::{syst::SystClkSource, SCB};
+use cortex_m_rt::entry;
+use embassy_futures::select::select;
+use embassy_stm32::{
+    bind_interrupts,
+    flash::Flash,
+    gpio::{Level, Output, Speed},
+    peripherals::USB,
+    time::Hertz,
+    usb::Driver,
+    Config,
+};
+use embassy_usb::Builder;
+
+const SYSCLK: u32 = 72_000_000;
+
+bind_interrupts!(
+    struct Irqs {
+        USB_LP_CAN1_RX0 => embassy_stm32::usb::InterruptHandler<USB>;
+    }
+);
+
+#[entry]
+fn main() -> ! {
+    let mut config = Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz::mhz(8),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV1,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+    }
+    let mut p = embassy_stm32::init(config);
+    let mut flash = Flash::new_blocking(p.FLASH);
+
+    // SAFETY: the word is reserved in the memory.x of both programs.
+    let requested = unsafe {
+        let request = DFU_REQUEST_ADDRESS as *mut u32;
+        let requested = request.read_volatile() == DFU_REQUEST;
+        request.write_volatile(0);
+        requested
+    };
+    // A flash error leaves the bootloader in DFU mode, where the download
+    // rewrites the boot state.
+    if !requested && matches!(banana_boot::boot(&mut flash), Ok(Boot::Firmware)) {
+        // SAFETY: `boot` checked the vector table, or the CRC of a download.
+        unsafe { start_firmware() }
+    }
+
+    {
+        // The host has to see a disconnect after the reset.
+        let _dp = Output::new(p.PA12.reborrow(), Level::Low, Speed::Low);
+        cortex_m::asm::delay(SYSCLK / 100);
+    }
+
+    let driver = Driver::new(p.USB, Irqs, p.PA12, p.PA11);
+    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    config.manufacturer = Some("Tnze");
+    config.product = Some("Banana RNG DFU");
+
+    let mut config_descriptor = [0; 64];
+    let mut bos_descriptor = [0; 32];
+    let mut msos_descriptor = [0; 256];
+    // Holds a whole download block.
+    let mut control_buf = [0; dfu::TRANSFER_SIZE as usize];
+    let mut dfu_mode = dfu::Dfu::new(flash);
+
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    // Lets Windows bind WinUSB to the DFU interface without an INF file.
+    builder.msos_descriptor(embassy_usb::msos::windows_version::WIN8_1, 0x20);
+    dfu::new(&mut builder, &mut dfu_mode);
+    let mut usb = builder.build();
+
+    let manifested = async {
+        dfu::MANIFESTED.wait().await;
+        // Let the status reply go out first. There is no time driver, it
+        // would keep running into the firmware, so SysTick counts 100 ms and
+        // `block_on` polls it.
+        let mut syst = cortex_m::Peripherals::take().unwrap().SYST;
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(SYSCLK / 10);
+        syst.clear_current();
+        syst.enable_counter();
+        poll_fn(|_| {
+            if syst.has_wrapped() {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await
+    };
+    embassy_futures::block_on(select(usb.run(), manifested));
+    SCB::sys_reset()
+}
+
+/// Jumps to the reset handler of the firmware.
+unsafe fn start_firmware() -> ! {
+    let address = FLASH_BASE + APP_OFFSET;
+    let peripherals = cortex_m::Peripherals::steal();
+    peripherals.SCB.vtor.write(address);
+    cortex_m::asm::bootload(address as *const u32)
+}
+
+/// Resets into DFU mode, a half written page is caught by the boot checks.
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    SCB::sys_reset()
+}
@@ -0,0 +1,88 @@
+//! The vendor interface streaming random bytes, and the ChaosKey personality.
+//!
+//! The interface is a vendor-specific function with a single bulk IN
+//! endpoint. No kernel driver binds to it on Linux and the MS OS 2.0
+//! descriptors make Windows load WinUSB, so libusb can claim it directly.
+//!
+//! With the ChaosKey personality the device takes the USB IDs of the Altus
+//! Metrum ChaosKey and has no interface associations, so the Linux
+//! `chaoskey` driver binds to it. The driver reads the first bulk IN endpoint
+//! it finds, one packet per transfer, and feeds the kernel entropy pool.
+//!
+//! Descriptors and endpoint only, so the crate builds and is tested on the
+//! host against a software USB device.
+
+#![no_std]
+#![allow(async_fn_in_trait)]
+
+use embassy_usb::{
+    driver::{Driver, EndpointError, EndpointIn},
+    msos, Builder, Config,
+};
+
+/// Vendor and product ID of the Altus Metrum ChaosKey.
+pub const CHAOSKEY_ID: (u16, u16) = (0x1d50, 0x60c6);
+
+/// Bulk IN packet size of the ChaosKey, the `chaoskey` driver reads one
+/// packet per transfer.
+pub const CHAOSKEY_MAX_PACKET_SIZE: u16 = 64;
+
+const DEVICE_INTERFACE_GUIDS: &[&str] = &["{5ef4b2a8-6d3c-4f0e-9b1a-42c7e0d1a9b3}"];
+
+/// Random bytes streamed by [`transfer`].
+pub trait Source {
+    type Error;
+
+    /// Fills `buf`, fails once the source stopped for good.
+    async fn fill(&mut self, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// Device configuration of the ChaosKey personality, strings are up to the
+/// caller.
+pub fn config<'a>() -> Config<'a> {
+    let mut config = Config::new(CHAOSKEY_ID.0, CHAOSKEY_ID.1);
+    // No interface associations, like the real ChaosKey.
+    config.composite_with_iads = false;
+    config.device_class = 0x00;
+    config.device_sub_class = 0x00;
+    config.device_protocol = 0x00;
+    config
+}
+
+/// Adds the vendor interface, returns its number and its bulk IN endpoint.
+///
+/// The WinUSB features need [`Builder::msos_descriptor`] to be set.
+pub fn interface<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    max_packet_size: u16,
+) -> (u8, D::EndpointIn) {
+    let mut function = builder.function(0xFF, 0, 0);
+    function.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+    function.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+        "DeviceInterfaceGUIDs",
+        msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
+    ));
+    let mut interface = function.interface();
+    let number = interface.interface_number().into();
+    let mut alt = interface.alt_setting(0xFF, 0, 0, None);
+    (number, alt.endpoint_bulk_in(None, max_packet_size))
+}
+
+/// Streams `source` one packet at a time for as long as the host keeps
+/// reading, returns the error once the source fails.
+pub async fn transfer<S: Source>(mut endpoint: impl EndpointIn, mut source: S) -> S::Error {
+    let mut random = [0u8; CHAOSKEY_MAX_PACKET_SIZE as usize];
+    let len = usize::from(endpoint.info().max_packet_size).min(random.len());
+    let random = &mut random[..len];
+    loop {
+        endpoint.wait_enabled().await;
+        loop {
+            if let Err(error) = source.fill(random).await {
+                return error;
+            }
+            if let Err(EndpointError::Disabled) = endpoint.write(random).await {
+                break;
+            }
+        }
+    }
+}
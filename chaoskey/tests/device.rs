@@ -0,0 +1,492 @@
+//! Enumerates the ChaosKey personality on a software USB device and reads the
+//! bulk IN endpoint, the way the Linux `chaoskey` driver does.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, VecDeque},
+    convert::Infallible,
+    future::poll_fn,
+    rc::Rc,
+    task::Poll,
+};
+
+use banana_chaoskey::{Source, CHAOSKEY_ID, CHAOSKEY_MAX_PACKET_SIZE};
+use embassy_futures::{
+    block_on,
+    select::{select3, Either3},
+};
+use embassy_usb::{
+    driver::{
+        self, Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo,
+        EndpointType, Event, Unsupported,
+    },
+    msos, Builder,
+};
+
+const DESCRIPTOR_DEVICE: u8 = 0x01;
+const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+const DESCRIPTOR_INTERFACE: u8 = 0x04;
+const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+const DESCRIPTOR_INTERFACE_ASSOCIATION: u8 = 0x0B;
+
+const GET_DESCRIPTOR: u8 = 0x06;
+const SET_ADDRESS: u8 = 0x05;
+const SET_CONFIGURATION: u8 = 0x09;
+
+/// How a control transfer ended.
+#[derive(Debug, PartialEq)]
+enum Handshake {
+    Ack,
+    Stall,
+}
+
+/// The bus as seen from both ends.
+#[derive(Default)]
+struct Wire {
+    events: VecDeque<Event>,
+    setup: Option<[u8; 8]>,
+    data_out: Vec<u8>,
+    data_in: Vec<u8>,
+    handshake: Option<Handshake>,
+    enabled: BTreeSet<u8>,
+    /// The packet an IN endpoint holds until the host fetches it.
+    bulk_in: Option<(u8, Vec<u8>)>,
+}
+
+type Shared = Rc<RefCell<Wire>>;
+
+/// Waits until `f` has something, the executor of `block_on` keeps polling.
+async fn wait<T>(mut f: impl FnMut() -> Option<T>) -> T {
+    poll_fn(|_| f().map_or(Poll::Pending, Poll::Ready)).await
+}
+
+struct SoftDriver {
+    wire: Shared,
+    next_endpoint: usize,
+}
+
+struct SoftBus {
+    wire: Shared,
+}
+
+struct SoftControl {
+    wire: Shared,
+    max_packet_size: usize,
+}
+
+struct SoftEndpoint {
+    wire: Shared,
+    info: EndpointInfo,
+}
+
+impl SoftDriver {
+    fn alloc(
+        &mut self,
+        direction: Direction,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> SoftEndpoint {
+        self.next_endpoint += 1;
+        SoftEndpoint {
+            wire: self.wire.clone(),
+            info: EndpointInfo {
+                addr: EndpointAddress::from_parts(self.next_endpoint, direction),
+                ep_type,
+                max_packet_size,
+                interval_ms,
+            },
+        }
+    }
+}
+
+impl<'a> driver::Driver<'a> for SoftDriver {
+    type EndpointOut = SoftEndpoint;
+    type EndpointIn = SoftEndpoint;
+    type ControlPipe = SoftControl;
+    type Bus = SoftBus;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<SoftEndpoint, EndpointAllocError> {
+        Ok(self.alloc(Direction::Out, ep_type, max_packet_size, interval_ms))
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        _ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<SoftEndpoint, EndpointAllocError> {
+        Ok(self.alloc(Direction::In, ep_type, max_packet_size, interval_ms))
+    }
+
+    fn start(self, control_max_packet_size: u16) -> (SoftBus, SoftControl) {
+        let bus = SoftBus {
+            wire: self.wire.clone(),
+        };
+        let control = SoftControl {
+            wire: self.wire,
+            max_packet_size: control_max_packet_size.into(),
+        };
+        (bus, control)
+    }
+}
+
+impl driver::Bus for SoftBus {
+    async fn enable(&mut self) {}
+
+    async fn disable(&mut self) {}
+
+    async fn poll(&mut self) -> Event {
+        wait(|| self.wire.borrow_mut().events.pop_front()).await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        let mut wire = self.wire.borrow_mut();
+        if enabled {
+            wire.enabled.insert(ep_addr.into());
+        } else {
+            wire.enabled.remove(&ep_addr.into());
+        }
+    }
+
+    fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {}
+
+    fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        Err(Unsupported)
+    }
+}
+
+impl driver::ControlPipe for SoftControl {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        wait(|| self.wire.borrow_mut().setup.take()).await
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        let mut wire = self.wire.borrow_mut();
+        let len = buf.len().min(wire.data_out.len());
+        buf[..len].copy_from_slice(&wire.data_out[..len]);
+        wire.data_out.drain(..len);
+        Ok(len)
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        last: bool,
+    ) -> Result<(), EndpointError> {
+        let mut wire = self.wire.borrow_mut();
+        wire.data_in.extend_from_slice(data);
+        if last {
+            wire.handshake = Some(Handshake::Ack);
+        }
+        Ok(())
+    }
+
+    async fn accept(&mut self) {
+        self.wire.borrow_mut().handshake = Some(Handshake::Ack);
+    }
+
+    async fn reject(&mut self) {
+        self.wire.borrow_mut().handshake = Some(Handshake::Stall);
+    }
+
+    async fn accept_set_address(&mut self, _addr: u8) {
+        self.accept().await;
+    }
+}
+
+impl driver::Endpoint for SoftEndpoint {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        let addr = u8::from(self.info.addr);
+        wait(|| self.wire.borrow().enabled.contains(&addr).then_some(())).await
+    }
+}
+
+impl driver::EndpointIn for SoftEndpoint {
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        assert!(buf.len() <= self.info.max_packet_size.into());
+        let addr = u8::from(self.info.addr);
+        wait(|| {
+            let mut wire = self.wire.borrow_mut();
+            if !wire.enabled.contains(&addr) {
+                return Some(Err(EndpointError::Disabled));
+            }
+            if wire.bulk_in.is_some() {
+                return None;
+            }
+            wire.bulk_in = Some((addr, buf.to_vec()));
+            Some(Ok(()))
+        })
+        .await
+    }
+}
+
+impl driver::EndpointOut for SoftEndpoint {
+    async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+        unimplemented!("the vendor interface has no OUT endpoint")
+    }
+}
+
+/// The host end of the wire.
+struct Host {
+    wire: Shared,
+}
+
+impl Host {
+    async fn control(&self, setup: [u8; 8], data_out: &[u8]) -> (Handshake, Vec<u8>) {
+        {
+            let mut wire = self.wire.borrow_mut();
+            wire.data_in.clear();
+            wire.data_out = data_out.to_vec();
+            wire.setup = Some(setup);
+        }
+        let handshake = wait(|| self.wire.borrow_mut().handshake.take()).await;
+        (handshake, self.wire.borrow_mut().data_in.split_off(0))
+    }
+
+    async fn get_descriptor(&self, descriptor_type: u8, length: u16) -> Vec<u8> {
+        let [low, high] = length.to_le_bytes();
+        let setup = [0x80, GET_DESCRIPTOR, 0, descriptor_type, 0, 0, low, high];
+        let (handshake, data) = self.control(setup, &[]).await;
+        assert_eq!(handshake, Handshake::Ack);
+        data
+    }
+
+    async fn set(&self, request: u8, value: u8) {
+        let setup = [0x00, request, value, 0, 0, 0, 0, 0];
+        assert_eq!(self.control(setup, &[]).await.0, Handshake::Ack);
+    }
+
+    /// Enumerates the device, returns its configuration descriptor.
+    async fn enumerate(&self) -> Vec<u8> {
+        self.get_descriptor(DESCRIPTOR_DEVICE, 64).await;
+        self.set(SET_ADDRESS, 5).await;
+        let header = self.get_descriptor(DESCRIPTOR_CONFIGURATION, 9).await;
+        let total = u16::from_le_bytes([header[2], header[3]]);
+        let configuration = self.get_descriptor(DESCRIPTOR_CONFIGURATION, total).await;
+        self.set(SET_CONFIGURATION, 1).await;
+        configuration
+    }
+
+    /// Fetches the next packet from the IN endpoint `addr`.
+    async fn bulk_in(&self, addr: u8) -> Vec<u8> {
+        wait(|| {
+            let mut wire = self.wire.borrow_mut();
+            match wire.bulk_in.take() {
+                Some((from, packet)) => {
+                    assert_eq!(from, addr);
+                    Some(packet)
+                }
+                None => None,
+            }
+        })
+        .await
+    }
+
+    /// Whether the device has a packet queued within a few polls.
+    async fn bulk_in_pending(&self) -> bool {
+        for _ in 0..100 {
+            if self.wire.borrow().bulk_in.is_some() {
+                return true;
+            }
+            embassy_futures::yield_now().await;
+        }
+        false
+    }
+}
+
+/// Counts up from zero, or stops after `limit` bytes.
+struct Counter {
+    next: u8,
+    limit: Option<usize>,
+}
+
+struct Stopped;
+
+impl Source for Counter {
+    type Error = Stopped;
+
+    async fn fill(&mut self, buf: &mut [u8]) -> Result<(), Stopped> {
+        if let Some(limit) = &mut self.limit {
+            *limit = limit.checked_sub(buf.len()).ok_or(Stopped)?;
+        }
+        for byte in buf {
+            *byte = self.next;
+            self.next = self.next.wrapping_add(1);
+        }
+        Ok(())
+    }
+}
+
+/// Runs the ChaosKey personality until the host `script` returns.
+fn with_chaoskey<T>(source: Counter, script: impl AsyncFnOnce(&Host) -> T) -> T {
+    let wire = Shared::default();
+    let driver = SoftDriver {
+        wire: wire.clone(),
+        next_endpoint: 0,
+    };
+    let config = banana_chaoskey::config();
+
+    let mut config_descriptor = [0; 256];
+    let mut bos_descriptor = [0; 256];
+    let mut msos_descriptor = [0; 512];
+    let mut control_buf = [0; 64];
+    let mut builder = Builder::new(
+        driver,
+        config,
+        &mut config_descriptor,
+        &mut bos_descriptor,
+        &mut msos_descriptor,
+        &mut control_buf,
+    );
+    builder.msos_descriptor(msos::windows_version::WIN8_1, 0x20);
+    let (_, endpoint) = banana_chaoskey::interface(&mut builder, CHAOSKEY_MAX_PACKET_SIZE);
+    let mut usb = builder.build();
+
+    wire.borrow_mut()
+        .events
+        .extend([Event::PowerDetected, Event::Reset]);
+    let host = Host { wire };
+    // The firmware stalls the endpoint once the source stops.
+    let transfer = async {
+        banana_chaoskey::transfer(endpoint, source).await;
+        std::future::pending::<Infallible>().await
+    };
+    block_on(async {
+        match select3(usb.run(), transfer, script(&host)).await {
+            Either3::Third(result) => result,
+        }
+    })
+}
+
+/// Splits a configuration descriptor into its descriptors.
+fn descriptors(mut configuration: &[u8]) -> Vec<&[u8]> {
+    let mut descriptors = Vec::new();
+    while let [len, ..] = *configuration {
+        let (descriptor, rest) = configuration.split_at(len.into());
+        descriptors.push(descriptor);
+        configuration = rest;
+    }
+    descriptors
+}
+
+fn endless() -> Counter {
+    Counter {
+        next: 0,
+        limit: None,
+    }
+}
+
+#[test]
+fn chaoskey_ids() {
+    let device = with_chaoskey(endless(), async |host| {
+        host.get_descriptor(DESCRIPTOR_DEVICE, 18).await
+    });
+    assert_eq!(device.len(), 18);
+    assert_eq!(device[1], DESCRIPTOR_DEVICE);
+    // Class, subclass and protocol come from the interfaces.
+    assert_eq!(device[4..7], [0, 0, 0]);
+    assert_eq!(u16::from_le_bytes([device[8], device[9]]), CHAOSKEY_ID.0);
+    assert_eq!(u16::from_le_bytes([device[10], device[11]]), CHAOSKEY_ID.1);
+    assert_eq!(CHAOSKEY_ID, (0x1d50, 0x60c6));
+}
+
+#[test]
+fn one_vendor_interface_without_associations() {
+    let configuration = with_chaoskey(endless(), async |host| host.enumerate().await);
+    let descriptors = descriptors(&configuration);
+    assert!(descriptors
+        .iter()
+        .all(|descriptor| descriptor[1] != DESCRIPTOR_INTERFACE_ASSOCIATION));
+    assert_eq!(descriptors[0][1], DESCRIPTOR_CONFIGURATION);
+    // bNumInterfaces
+    assert_eq!(descriptors[0][4], 1);
+
+    let interfaces: Vec<_> = descriptors
+        .iter()
+        .filter(|descriptor| descriptor[1] == DESCRIPTOR_INTERFACE)
+        .collect();
+    assert_eq!(interfaces.len(), 1);
+    // bNumEndpoints, then vendor-specific class, subclass 0, protocol 0.
+    assert_eq!(interfaces[0][4..8], [1, 0xFF, 0, 0]);
+}
+
+#[test]
+fn one_bulk_in_endpoint() {
+    let configuration = with_chaoskey(endless(), async |host| host.enumerate().await);
+    let endpoints: Vec<_> = descriptors(&configuration)
+        .into_iter()
+        .filter(|descriptor| descriptor[1] == DESCRIPTOR_ENDPOINT)
+        .collect();
+    assert_eq!(endpoints.len(), 1);
+    let endpoint = endpoints[0];
+    assert_eq!(endpoint[2] & 0x80, 0x80, "IN endpoint");
+    assert_eq!(endpoint[3], 0x02, "bulk endpoint");
+    assert_eq!(
+        u16::from_le_bytes([endpoint[4], endpoint[5]]),
+        CHAOSKEY_MAX_PACKET_SIZE
+    );
+}
+
+#[test]
+fn bulk_in_reads_random_bytes() {
+    let packets = with_chaoskey(endless(), async |host| {
+        // Nothing is sent before the host configures the device.
+        assert!(!host.bulk_in_pending().await);
+        let configuration = host.enumerate().await;
+        let endpoint = descriptors(&configuration)
+            .into_iter()
+            .find(|descriptor| descriptor[1] == DESCRIPTOR_ENDPOINT)
+            .unwrap()[2];
+        [host.bulk_in(endpoint).await, host.bulk_in(endpoint).await]
+    });
+    let expected: Vec<u8> = (0..=255).collect();
+    assert_eq!(packets[0], expected[..64]);
+    assert_eq!(packets[1], expected[64..128]);
+}
+
+#[test]
+fn stopped_source_sends_nothing() {
+    let sent = with_chaoskey(
+        Counter {
+            next: 0,
+            limit: Some(64),
+        },
+        async |host| {
+            let configuration = host.enumerate().await;
+            let endpoint = descriptors(&configuration)
+                .into_iter()
+                .find(|descriptor| descriptor[1] == DESCRIPTOR_ENDPOINT)
+                .unwrap()[2];
+            let first = host.bulk_in(endpoint).await;
+            (first, host.bulk_in_pending().await)
+        },
+    );
+    assert_eq!(sent.0.len(), 64);
+    assert!(!sent.1);
+}
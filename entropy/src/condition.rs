@@ -0,0 +1,135 @@
+//! SHA-256 conditioning component for full-entropy output.
+//!
+//! Raw noise samples are hashed as they arrive while an entropy credit
+//! counter adds up their assessed min-entropy. An output block is only
+//! released once at least twice its size has been credited, which makes it
+//! full entropy per SP 800-90B section 3.1.5.1.
+
+use sha2::{Digest, Sha256};
+
+pub const OUTPUT_BYTES: usize = 32;
+/// Min-entropy to credit before releasing one output block.
+const REQUIRED_CREDIT: f32 = 2. * (OUTPUT_BYTES * 8) as f32;
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    /// Min-entropy credited to the block being collected, in bits.
+    pub credit: f32,
+    /// Output blocks released since boot.
+    pub blocks: u32,
+}
+
+pub struct Conditioner {
+    hasher: Sha256,
+    stats: Stats,
+}
+
+impl Default for Conditioner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Conditioner {
+    pub fn new() -> Self {
+        Self {
+            hasher: Sha256::new(),
+            stats: Stats {
+                credit: 0.,
+                blocks: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Hashes `data`, crediting it with `min_entropy` bits.
+    pub fn absorb(&mut self, data: &[u8], min_entropy: f32) {
+        self.hasher.update(data);
+        self.stats.credit += min_entropy.min((data.len() * 8) as f32);
+    }
+
+    /// Returns the next output block once enough entropy has been credited.
+    pub fn squeeze(&mut self) -> Option<[u8; OUTPUT_BYTES]> {
+        if self.stats.credit < REQUIRED_CREDIT {
+            return None;
+        }
+        self.stats.credit = 0.;
+        self.stats.blocks += 1;
+        Some(self.hasher.finalize_reset().into())
+    }
+}
+
+/// Known-answer tests of SHA-256 and of the credit counter.
+pub fn self_test() -> bool {
+    const ABC: [u8; OUTPUT_BYTES] = [
+        0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22,
+        0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00,
+        0x15, 0xad,
+    ];
+    // SHA-256 over the little-endian samples 0..64.
+    const SAMPLES: [u8; OUTPUT_BYTES] = [
+        0x7a, 0x46, 0x44, 0x92, 0x8f, 0x3a, 0x08, 0xdb, 0x90, 0x52, 0x54, 0xfd, 0x7e, 0x5e, 0x53,
+        0xef, 0x19, 0xa4, 0x6d, 0x93, 0x2a, 0x2e, 0xcd, 0x37, 0x2b, 0x45, 0x46, 0x24, 0x13, 0xa8,
+        0x26, 0x19,
+    ];
+
+    if <[u8; OUTPUT_BYTES]>::from(Sha256::digest(b"abc")) != ABC {
+        return false;
+    }
+
+    let mut conditioner = Conditioner::new();
+    for sample in 0..64u64 {
+        if conditioner.squeeze().is_some() {
+            return false;
+        }
+        conditioner.absorb(&sample.to_le_bytes(), 8.);
+    }
+    conditioner.squeeze() == Some(SAMPLES) && conditioner.squeeze().is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer() {
+        assert!(self_test());
+    }
+
+    #[test]
+    fn credit_is_capped_by_data_size() {
+        let mut conditioner = Conditioner::new();
+        conditioner.absorb(&[0; 2], 100.);
+        assert_eq!(conditioner.stats().credit, 16.);
+    }
+
+    #[test]
+    fn releases_after_twice_the_output_size() {
+        let mut conditioner = Conditioner::new();
+        for _ in 0..511 {
+            conditioner.absorb(&[1], 1.);
+            assert!(conditioner.squeeze().is_none());
+        }
+        conditioner.absorb(&[1], 1.);
+        let block = conditioner.squeeze().unwrap();
+        assert_eq!(block, <[u8; OUTPUT_BYTES]>::from(Sha256::digest([1; 512])));
+        assert_eq!(conditioner.stats().blocks, 1);
+        assert_eq!(conditioner.stats().credit, 0.);
+    }
+
+    #[test]
+    fn uncredited_data_is_hashed() {
+        let mut conditioner = Conditioner::new();
+        conditioner.absorb(b"raw", 0.);
+        conditioner.absorb(&[0; 64], 512.);
+        let block = conditioner.squeeze().unwrap();
+        let mut hasher = Sha256::new();
+        hasher.update(b"raw");
+        hasher.update([0; 64]);
+        assert_eq!(block, <[u8; OUTPUT_BYTES]>::from(hasher.finalize()));
+    }
+}
@@ -0,0 +1,225 @@
+//! Debiasing extractors run on the raw interval comparison bits.
+//!
+//! Dead time and tube recovery make the raw bits slightly biased. Both
+//! extractors here output unbiased bits from independent biased input, at the
+//! cost of throughput: von Neumann keeps at most 1/4 of the input, iterated
+//! Peres recycles the discarded information and approaches the Shannon limit.
+
+/// Number of input bits the Peres extractor works on at once.
+const PERES_BLOCK_BITS: u32 = u64::BITS;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    VonNeumann,
+    Peres,
+}
+
+impl Method {
+    pub fn name(self) -> &'static str {
+        match self {
+            Method::VonNeumann => "von-neumann",
+            Method::Peres => "peres",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Method> {
+        [Method::VonNeumann, Method::Peres]
+            .into_iter()
+            .find(|method| method.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    pub method: Method,
+    pub bits_in: u64,
+    pub bits_out: u64,
+}
+
+impl Stats {
+    /// Output bits per input bit.
+    pub fn efficiency(&self) -> f32 {
+        if self.bits_in == 0 {
+            return f32::NAN;
+        }
+        self.bits_out as f32 / self.bits_in as f32
+    }
+}
+
+pub struct Debiaser {
+    stats: Stats,
+    block: u64,
+    len: u32,
+}
+
+impl Debiaser {
+    pub const fn new(method: Method) -> Self {
+        Self {
+            stats: Stats {
+                method,
+                bits_in: 0,
+                bits_out: 0,
+            },
+            block: 0,
+            len: 0,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    /// Switches the extractor, dropping any partially collected input.
+    pub fn set_method(&mut self, method: Method) {
+        *self = Self::new(method);
+    }
+
+    /// Feeds one raw bit, calling `out` for every unbiased bit produced.
+    pub fn push(&mut self, bit: bool, mut out: impl FnMut(bool)) {
+        self.stats.bits_in += 1;
+        self.block |= (bit as u64) << self.len;
+        self.len += 1;
+
+        let block_bits = match self.stats.method {
+            Method::VonNeumann => 2,
+            Method::Peres => PERES_BLOCK_BITS,
+        };
+        if self.len < block_bits {
+            return;
+        }
+
+        let bits_out = &mut self.stats.bits_out;
+        peres(
+            self.block,
+            self.len,
+            self.stats.method == Method::Peres,
+            &mut |bit| {
+                *bits_out += 1;
+                out(bit)
+            },
+        );
+        self.block = 0;
+        self.len = 0;
+    }
+}
+
+/// Applies von Neumann to the pairs of `bits`, then recurses on the XOR and
+/// the equal-pair sequences when `iterate` is set.
+///
+/// `bits` holds `len` bits, least significant first. Each pair `ab` with
+/// `a != b` outputs `a`, so `01` gives 0 and `10` gives 1.
+fn peres(bits: u64, len: u32, iterate: bool, out: &mut impl FnMut(bool)) {
+    let mut xors = 0u64;
+    let mut equals = 0u64;
+    let mut equals_len = 0;
+    for i in 0..len / 2 {
+        let a = bits >> (2 * i) & 1 == 1;
+        let b = bits >> (2 * i + 1) & 1 == 1;
+        if a != b {
+            out(a);
+        } else {
+            equals |= (a as u64) << equals_len;
+            equals_len += 1;
+        }
+        xors |= ((a ^ b) as u64) << i;
+    }
+    if iterate && len >= 4 {
+        peres(xors, len / 2, true, out);
+        peres(equals, equals_len, true, out);
+    }
+}
+
+/// Known-answer test of both extractors, run before the startup self-test.
+pub fn self_test() -> bool {
+    const INPUT: u64 = 0x0123_4567_89ab_cdef;
+    const VECTORS: [(Method, u64, u32); 2] = [
+        (Method::VonNeumann, 0xbd22, 16),
+        (Method::Peres, 0x01ba_96c3_bd22, 41),
+    ];
+    VECTORS.iter().all(|&(method, expected, expected_len)| {
+        let mut debiaser = Debiaser::new(method);
+        let (mut output, mut len) = (0u64, 0);
+        for i in 0..u64::BITS {
+            debiaser.push(INPUT >> i & 1 == 1, |bit| {
+                output |= (bit as u64) << len;
+                len += 1;
+            });
+        }
+        output == expected && len == expected_len
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(method: Method, input: &[bool]) -> ([bool; 64], usize) {
+        let mut debiaser = Debiaser::new(method);
+        let (mut output, mut len) = ([false; 64], 0);
+        for &bit in input {
+            debiaser.push(bit, |bit| {
+                output[len] = bit;
+                len += 1;
+            });
+        }
+        (output, len)
+    }
+
+    #[test]
+    fn known_answer() {
+        assert!(self_test());
+    }
+
+    #[test]
+    fn von_neumann_pairs() {
+        let (output, len) = run(
+            Method::VonNeumann,
+            &[false, true, true, false, true, true, false, false, true],
+        );
+        // 01 gives 0, 10 gives 1, equal pairs and the odd bit give nothing.
+        assert_eq!(&output[..len], &[false, true]);
+    }
+
+    #[test]
+    fn peres_waits_for_a_block() {
+        let input = [true, false].repeat(32);
+        let (_, len) = run(Method::Peres, &input[..63]);
+        assert_eq!(len, 0);
+        let (output, len) = run(Method::Peres, &input);
+        // All pairs are 10, the XOR and equal sequences carry nothing more.
+        assert_eq!(&output[..len], &[true; 32]);
+    }
+
+    #[test]
+    fn stats() {
+        let mut debiaser = Debiaser::new(Method::VonNeumann);
+        for bit in [false, true, true, true] {
+            debiaser.push(bit, |_| {});
+        }
+        let stats = debiaser.stats();
+        assert_eq!((stats.bits_in, stats.bits_out), (4, 1));
+        assert_eq!(stats.efficiency(), 0.25);
+        assert!(Debiaser::new(Method::Peres).stats().efficiency().is_nan());
+    }
+
+    #[test]
+    fn set_method_drops_partial_input() {
+        let mut debiaser = Debiaser::new(Method::VonNeumann);
+        debiaser.push(true, |_| {});
+        debiaser.set_method(Method::VonNeumann);
+        let mut output = None;
+        debiaser.push(false, |bit| output = Some(bit));
+        assert_eq!(output, None);
+        assert_eq!(debiaser.stats().bits_in, 1);
+    }
+
+    #[test]
+    fn method_names() {
+        for method in [Method::VonNeumann, Method::Peres] {
+            assert_eq!(Method::from_name(method.name()), Some(method));
+        }
+        assert_eq!(Method::from_name("xor"), None);
+    }
+}
@@ -0,0 +1,175 @@
+//! HMAC_DRBG with SHA-256 from NIST SP 800-90A section 10.1.2.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const OUTLEN: usize = 32;
+/// Generate requests allowed between reseeds; SP 800-90A allows up to 2^48.
+const RESEED_INTERVAL: u64 = 1 << 32;
+/// Largest single generate request, 2^19 bits.
+pub const MAX_REQUEST_BYTES: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The reseed interval is exhausted, reseed before generating more.
+    ReseedRequired,
+    RequestTooLarge,
+}
+
+pub struct HmacDrbg {
+    key: [u8; OUTLEN],
+    value: [u8; OUTLEN],
+    reseed_counter: u64,
+}
+
+impl HmacDrbg {
+    pub fn new(entropy: &[u8], nonce: &[u8], personalization: &[u8]) -> Self {
+        let mut drbg = Self {
+            key: [0x00; OUTLEN],
+            value: [0x01; OUTLEN],
+            reseed_counter: 1,
+        };
+        drbg.update(&[entropy, nonce, personalization]);
+        drbg
+    }
+
+    /// Generate requests since the last (re)seed.
+    pub fn reseed_counter(&self) -> u64 {
+        self.reseed_counter
+    }
+
+    pub fn reseed(&mut self, entropy: &[u8], additional: &[u8]) {
+        self.update(&[entropy, additional]);
+        self.reseed_counter = 1;
+    }
+
+    pub fn generate(&mut self, output: &mut [u8], additional: &[u8]) -> Result<(), Error> {
+        if output.len() > MAX_REQUEST_BYTES {
+            return Err(Error::RequestTooLarge);
+        }
+        if self.reseed_counter > RESEED_INTERVAL {
+            return Err(Error::ReseedRequired);
+        }
+        if !additional.is_empty() {
+            self.update(&[additional]);
+        }
+        for chunk in output.chunks_mut(OUTLEN) {
+            self.value = hmac(&self.key, &[&self.value]);
+            chunk.copy_from_slice(&self.value[..chunk.len()]);
+        }
+        self.update(&[additional]);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+
+    /// The HMAC_DRBG update function, `provided` is concatenated.
+    fn update(&mut self, provided: &[&[u8]]) {
+        let provided_empty = provided.iter().all(|data| data.is_empty());
+        for separator in [0x00, 0x01] {
+            if separator == 0x01 && provided_empty {
+                break;
+            }
+            let mut mac = new_mac(&self.key);
+            mac.update(&self.value);
+            mac.update(&[separator]);
+            for data in provided {
+                mac.update(data);
+            }
+            self.key = mac.finalize().into_bytes().into();
+            self.value = hmac(&self.key, &[&self.value]);
+        }
+    }
+}
+
+fn new_mac(key: &[u8; OUTLEN]) -> Hmac<Sha256> {
+    Hmac::new_from_slice(key).expect("HMAC accepts keys of any size")
+}
+
+fn hmac(key: &[u8; OUTLEN], data: &[&[u8]]) -> [u8; OUTLEN] {
+    let mut mac = new_mac(key);
+    for data in data {
+        mac.update(data);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Known-answer test from the NIST CAVP HMAC_DRBG SHA-256 vectors, without
+/// prediction resistance, personalization or additional input.
+pub fn self_test() -> bool {
+    const ENTROPY: [u8; 32] = [
+        0xca, 0x85, 0x19, 0x11, 0x34, 0x93, 0x84, 0xbf, 0xfe, 0x89, 0xde, 0x1c, 0xbd, 0xc4, 0x6e,
+        0x68, 0x31, 0xe4, 0x4d, 0x34, 0xa4, 0xfb, 0x93, 0x5e, 0xe2, 0x85, 0xdd, 0x14, 0xb7, 0x1a,
+        0x74, 0x88,
+    ];
+    const NONCE: [u8; 16] = [
+        0x65, 0x9b, 0xa9, 0x6c, 0x60, 0x1d, 0xc6, 0x9f, 0xc9, 0x02, 0x94, 0x08, 0x05, 0xec, 0x0c,
+        0xa8,
+    ];
+    const EXPECTED: [u8; 128] = [
+        0xe5, 0x28, 0xe9, 0xab, 0xf2, 0xde, 0xce, 0x54, 0xd4, 0x7c, 0x7e, 0x75, 0xe5, 0xfe, 0x30,
+        0x21, 0x49, 0xf8, 0x17, 0xea, 0x9f, 0xb4, 0xbe, 0xe6, 0xf4, 0x19, 0x96, 0x97, 0xd0, 0x4d,
+        0x5b, 0x89, 0xd5, 0x4f, 0xbb, 0x97, 0x8a, 0x15, 0xb5, 0xc4, 0x43, 0xc9, 0xec, 0x21, 0x03,
+        0x6d, 0x24, 0x60, 0xb6, 0xf7, 0x3e, 0xba, 0xd0, 0xdc, 0x2a, 0xba, 0x6e, 0x62, 0x4a, 0xbf,
+        0x07, 0x74, 0x5b, 0xc1, 0x07, 0x69, 0x4b, 0xb7, 0x54, 0x7b, 0xb0, 0x99, 0x5f, 0x70, 0xde,
+        0x25, 0xd6, 0xb2, 0x9e, 0x2d, 0x30, 0x11, 0xbb, 0x19, 0xd2, 0x76, 0x76, 0xc0, 0x71, 0x62,
+        0xc8, 0xb5, 0xcc, 0xde, 0x06, 0x68, 0x96, 0x1d, 0xf8, 0x68, 0x03, 0x48, 0x2c, 0xb3, 0x7e,
+        0xd6, 0xd5, 0xc0, 0xbb, 0x8d, 0x50, 0xcf, 0x1f, 0x50, 0xd4, 0x76, 0xaa, 0x04, 0x58, 0xbd,
+        0xab, 0xa8, 0x06, 0xf4, 0x8b, 0xe9, 0xdc, 0xb8,
+    ];
+
+    let mut drbg = HmacDrbg::new(&ENTROPY, &NONCE, &[]);
+    let mut output = [0; EXPECTED.len()];
+    drbg.generate(&mut output, &[]).is_ok()
+        && drbg.generate(&mut output, &[]).is_ok()
+        && output == EXPECTED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answer() {
+        assert!(self_test());
+    }
+
+    #[test]
+    fn request_too_large() {
+        let mut drbg = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        let mut output = [0; MAX_REQUEST_BYTES + 1];
+        assert_eq!(drbg.generate(&mut output, &[]), Err(Error::RequestTooLarge));
+        assert_eq!(drbg.reseed_counter(), 1);
+    }
+
+    #[test]
+    fn reseed_required() {
+        let mut drbg = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        drbg.reseed_counter = RESEED_INTERVAL;
+        let mut output = [0; 32];
+        assert_eq!(drbg.generate(&mut output, &[]), Ok(()));
+        assert_eq!(drbg.generate(&mut output, &[]), Err(Error::ReseedRequired));
+        drbg.reseed(&[1; 32], &[]);
+        assert_eq!(drbg.reseed_counter(), 1);
+        assert_eq!(drbg.generate(&mut output, &[]), Ok(()));
+    }
+
+    #[test]
+    fn inputs_change_the_output() {
+        let generate = |drbg: &mut HmacDrbg, additional: &[u8]| {
+            let mut output = [0; 32];
+            drbg.generate(&mut output, additional).unwrap();
+            output
+        };
+        let mut a = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        let mut b = HmacDrbg::new(&[0; 32], &[0; 16], b"personalization");
+        assert_ne!(generate(&mut a, &[]), generate(&mut b, &[]));
+        let mut c = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        let mut d = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        assert_ne!(generate(&mut c, &[]), generate(&mut d, b"additional"));
+        c.reseed(&[1; 32], &[]);
+        d = HmacDrbg::new(&[0; 32], &[0; 16], &[]);
+        generate(&mut d, &[]);
+        assert_ne!(generate(&mut c, &[]), generate(&mut d, &[]));
+    }
+}
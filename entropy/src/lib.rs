@@ -0,0 +1,12 @@
+//! The deterministic stages between the Geiger noise source and the random
+//! output of the Banana RNG.
+//!
+//! The crate is `no_std` and has no hardware dependencies, so the
+//! known-answer tests the firmware runs at startup also run on the host with
+//! `cargo test`. The `defmt` feature derives `defmt::Format` for the firmware.
+
+#![no_std]
+
+pub mod condition;
+pub mod debias;
+pub mod drbg;
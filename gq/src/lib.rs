@@ -0,0 +1,224 @@
+//! The GQ-RFC1201 serial protocol of the GQ GMC Geiger counters.
+//!
+//! Commands are framed as `<NAME>>` without a line ending, replies are raw
+//! big-endian binary. Enough of the protocol is implemented for logging
+//! software like GeigerLog and GQ Data Viewer to read the count rate. The
+//! device reports itself as a GMC-300 so those tools use the 2-byte CPM reply.
+//!
+//! Framing and encoding only, so the crate builds and is tested on the host.
+
+#![no_std]
+
+/// Reply to `<GETVER>>`, always 14 bytes.
+pub const VERSION: &[u8; 14] = b"GMC-300Re 4.54";
+
+/// Longest frame kept, far longer than any supported command.
+const MAX_FRAME: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Reply with [`VERSION`].
+    GetVersion,
+    /// Reply with [`cpm`].
+    GetCpm,
+    /// Reply with [`cps`].
+    GetCps,
+    /// Reply with [`serial`].
+    GetSerial,
+    /// Start or stop sending [`cps`] every second, without a request.
+    Heartbeat(bool),
+    /// Reply with [`volts`] of the supply.
+    GetVolts,
+    Reboot,
+    /// No reply, the device cannot be switched off.
+    PowerOn,
+    PowerOff,
+}
+
+impl Command {
+    fn from_name(name: &[u8]) -> Option<Command> {
+        Some(match name {
+            b"GETVER" => Command::GetVersion,
+            b"GETCPM" => Command::GetCpm,
+            b"GETCPS" => Command::GetCps,
+            b"GETSERIAL" => Command::GetSerial,
+            b"HEARTBEAT1" => Command::Heartbeat(true),
+            b"HEARTBEAT0" => Command::Heartbeat(false),
+            b"GETVOLT" => Command::GetVolts,
+            b"REBOOT" => Command::Reboot,
+            b"POWERON" => Command::PowerOn,
+            b"POWEROFF" => Command::PowerOff,
+            _ => return None,
+        })
+    }
+}
+
+/// A complete frame naming a command that is not supported.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unsupported(heapless::Vec<u8, MAX_FRAME>);
+
+impl Unsupported {
+    /// The command name, without the framing.
+    pub fn name(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Finds `<NAME>>` frames in a byte stream that may also carry other input.
+pub struct Parser {
+    frame: heapless::Vec<u8, MAX_FRAME>,
+    receiving: bool,
+}
+
+impl Parser {
+    pub const fn new() -> Self {
+        Self {
+            frame: heapless::Vec::new(),
+            receiving: false,
+        }
+    }
+
+    /// Whether a frame has been started but not completed.
+    pub fn receiving(&self) -> bool {
+        self.receiving
+    }
+
+    /// Feeds one received byte, returning the command once its frame is
+    /// complete.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, Unsupported>> {
+        if byte == b'<' {
+            self.frame.clear();
+            self.receiving = true;
+            return None;
+        }
+        if !self.receiving {
+            return None;
+        }
+        if self.frame.push(byte).is_err() {
+            self.receiving = false;
+            return None;
+        }
+        let name = self.frame.strip_suffix(b">>")?;
+        self.receiving = false;
+        Some(Command::from_name(name).ok_or_else(|| {
+            let mut unsupported = heapless::Vec::new();
+            let _ = unsupported.extend_from_slice(name);
+            Unsupported(unsupported)
+        }))
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Counts per minute, saturated to 16 bits.
+pub fn cpm(cpm: f32) -> [u8; 2] {
+    // NaN, before there are enough pulses, becomes 0.
+    (cpm as u16).to_be_bytes()
+}
+
+/// Counts per second. The two upper bits are reserved, so the count is
+/// saturated to 14 bits.
+pub fn cps(cps: u32) -> [u8; 2] {
+    (cps.min(0x3fff) as u16).to_be_bytes()
+}
+
+/// The 7-byte serial number, taken from the start of the device `uid`.
+pub fn serial(uid: &[u8]) -> [u8; 7] {
+    core::array::from_fn(|i| uid.get(i).copied().unwrap_or(0))
+}
+
+/// Voltage in tenths of a volt.
+pub fn volts(volts: f32) -> [u8; 1] {
+    [(volts * 10.) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> heapless::Vec<Result<Command, Unsupported>, 8> {
+        let mut parser = Parser::new();
+        let mut commands = heapless::Vec::new();
+        for &byte in input {
+            if let Some(command) = parser.push(byte) {
+                commands.push(command).unwrap();
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(parse(b"<GETVER>>"), [Ok(Command::GetVersion)]);
+        assert_eq!(
+            parse(b"<GETCPM>><HEARTBEAT1>><HEARTBEAT0>>"),
+            [
+                Ok(Command::GetCpm),
+                Ok(Command::Heartbeat(true)),
+                Ok(Command::Heartbeat(false)),
+            ]
+        );
+    }
+
+    #[test]
+    fn partial_frame() {
+        let mut parser = Parser::new();
+        assert!(!parser.receiving());
+        for &byte in b"<GETCPS>" {
+            assert_eq!(parser.push(byte), None);
+        }
+        assert!(parser.receiving());
+        assert_eq!(parser.push(b'>'), Some(Ok(Command::GetCps)));
+        assert!(!parser.receiving());
+    }
+
+    #[test]
+    fn garbage_around_frames() {
+        assert_eq!(
+            parse(b"status\r\n<GETSERIAL>>> >><GETVOLT>>x"),
+            [Ok(Command::GetSerial), Ok(Command::GetVolts)]
+        );
+    }
+
+    #[test]
+    fn restarts_on_open() {
+        assert_eq!(parse(b"<GET<REBOOT>>"), [Ok(Command::Reboot)]);
+    }
+
+    #[test]
+    fn unsupported() {
+        let commands = parse(b"<GETGYRO>>");
+        let [Err(unsupported)] = commands.as_slice() else {
+            panic!("{commands:?}");
+        };
+        assert_eq!(unsupported.name(), b"GETGYRO");
+        assert_eq!(parse(b"<>>"), [Err(Unsupported(heapless::Vec::new()))]);
+    }
+
+    #[test]
+    fn overlong_frame() {
+        assert_eq!(parse(b"<GETVERGETVERGETVER>>"), []);
+        assert_eq!(
+            parse(b"<GETVERGETVERGETVER>><POWEROFF>>"),
+            [Ok(Command::PowerOff)]
+        );
+    }
+
+    #[test]
+    fn encodings() {
+        assert_eq!(cpm(25.9), [0x00, 0x19]);
+        assert_eq!(cpm(300.), [0x01, 0x2c]);
+        assert_eq!(cpm(1e6), [0xff, 0xff]);
+        assert_eq!(cpm(f32::NAN), [0x00, 0x00]);
+        assert_eq!(cps(0x1234), [0x12, 0x34]);
+        assert_eq!(cps(0x4000), [0x3f, 0xff]);
+        assert_eq!(serial(&[1, 2, 3, 4, 5, 6, 7, 8]), [1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(serial(&[1, 2]), [1, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(volts(5.), [50]);
+        assert_eq!(VERSION.len(), 14);
+    }
+}
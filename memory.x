@@ -1,7 +1,11 @@
 /* Linker script for the STM32F103CBT6 */
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 124K
+  /* The bootloader and its boot state page, see the banana-boot crate */
+  BOOTLOADER : ORIGIN = 0x08000000, LENGTH = 24K
+  FLASH : ORIGIN = 0x08006000, LENGTH = 100K
   USER_CONFIG : ORIGIN = 0x0801F000, LENGTH = 4K
-  RAM : ORIGIN = 0x20000000, LENGTH = 20K
+  RAM : ORIGIN = 0x20000000, LENGTH = 20K - 4
+  /* Read by the bootloader after a reset, see DFU_REQUEST_ADDRESS */
+  BOOT_REQUEST : ORIGIN = 0x20004FFC, LENGTH = 4
 }
@@ -0,0 +1,29 @@
+"""Appends the trailer the bootloader checks to a firmware binary.
+
+    python3 python/dfu_image.py app.bin app.img
+"""
+
+import struct
+import sys
+import zlib
+
+MAGIC = b"BNNA"
+# Flash from 0x08006000 to the storage at 0x0801F000, minus the trailer.
+MAX_SIZE = 100 * 1024 - 12
+
+
+def main():
+    source, destination = sys.argv[1:]
+    with open(source, "rb") as f:
+        image = f.read()
+    # Whole words, as erased flash reads.
+    image += b"\xff" * (-len(image) % 4)
+    if len(image) > MAX_SIZE:
+        sys.exit(f"{source}: {len(image)} bytes, the firmware region holds {MAX_SIZE}")
+    trailer = MAGIC + struct.pack("<II", len(image), zlib.crc32(image))
+    with open(destination, "wb") as f:
+        f.write(image + trailer)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,154 @@
+//! Fixed-size byte queue between the USB and UART sides of the bridge.
+//!
+//! The crate is `no_std` and has no dependencies, so the queue builds and
+//! runs on the host as well. The firmware adds the waiting around it.
+
+#![no_std]
+
+/// A FIFO of up to `N` bytes in a ring buffer.
+pub struct Queue<const N: usize> {
+    buf: [u8; N],
+    /// Index of the oldest byte.
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> Queue<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Room left, in bytes.
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    /// Appends as much of `data` as fits and returns how many bytes that
+    /// was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        let tail = (self.head + self.len) % N.max(1);
+        let first = n.min(N - tail);
+        self.buf[tail..tail + first].copy_from_slice(&data[..first]);
+        self.buf[..n - first].copy_from_slice(&data[first..n]);
+        self.len += n;
+        n
+    }
+
+    /// Removes up to `out.len()` of the oldest bytes into `out` and returns
+    /// how many there were.
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        let first = n.min(N - self.head);
+        out[..first].copy_from_slice(&self.buf[self.head..self.head + first]);
+        out[first..n].copy_from_slice(&self.buf[..n - first]);
+        self.head = (self.head + n) % N.max(1);
+        self.len -= n;
+        n
+    }
+}
+
+impl<const N: usize> Default for Queue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo() {
+        let mut queue = Queue::<8>::new();
+        assert!(queue.is_empty());
+        assert_eq!(queue.write(b"abc"), 3);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.free(), 5);
+        let mut out = [0; 8];
+        assert_eq!(queue.read(&mut out), 3);
+        assert_eq!(&out[..3], b"abc");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut queue = Queue::<8>::new();
+        let mut out = [0; 8];
+        assert_eq!(queue.write(b"abcdef"), 6);
+        assert_eq!(queue.read(&mut out[..4]), 4);
+        assert_eq!(queue.write(b"ghijkl"), 6);
+        assert_eq!(queue.len(), 8);
+        assert_eq!(queue.read(&mut out), 8);
+        assert_eq!(&out, b"efghijkl");
+    }
+
+    #[test]
+    fn partial_write_when_full() {
+        let mut queue = Queue::<8>::new();
+        assert_eq!(queue.write(b"abcde"), 5);
+        assert_eq!(queue.write(b"fghij"), 3);
+        assert_eq!(queue.free(), 0);
+        assert_eq!(queue.write(b"k"), 0);
+        let mut out = [0; 8];
+        assert_eq!(queue.read(&mut out), 8);
+        assert_eq!(&out, b"abcdefgh");
+    }
+
+    #[test]
+    fn partial_read() {
+        let mut queue = Queue::<8>::new();
+        queue.write(b"abcdef");
+        let mut out = [0; 4];
+        assert_eq!(queue.read(&mut out[..2]), 2);
+        assert_eq!(&out[..2], b"ab");
+        assert_eq!(queue.read(&mut out), 4);
+        assert_eq!(&out, b"cdef");
+        assert_eq!(queue.read(&mut out), 0);
+    }
+
+    #[test]
+    fn clear() {
+        let mut queue = Queue::<8>::new();
+        queue.write(b"abcdef");
+        queue.read(&mut [0; 3]);
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.free(), 8);
+        assert_eq!(queue.write(b"12345678"), 8);
+        let mut out = [0; 8];
+        assert_eq!(queue.read(&mut out), 8);
+        assert_eq!(&out, b"12345678");
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut queue = Queue::<0>::new();
+        assert_eq!(queue.capacity(), 0);
+        assert_eq!(queue.free(), 0);
+        assert_eq!(queue.write(b"abc"), 0);
+        assert_eq!(queue.read(&mut [0; 4]), 0);
+        assert!(queue.is_empty());
+    }
+}
@@ -7,14 +7,38 @@ use embassy_stm32::{
     spi::Spi,
     Peri,
 };
-use embassy_sync::pubsub::DynSubscriber;
+use embassy_sync::{pubsub::DynSubscriber, watch::DynReceiver};
 use ssd1306::{
     mode::{TerminalModeAsync, TerminalModeError},
     prelude::*,
     Ssd1306Async,
 };
 
-use crate::geiger;
+use crate::{entropy, geiger};
+
+/// Longest `u64`.
+const U64_LEN: usize = 20;
+
+/// Longest `f32` written with `precision` decimals: sign, 39 integer digits,
+/// point and decimals. NaN and infinity are shorter.
+const fn f32_len(precision: usize) -> usize {
+    1 + 39 + 1 + precision
+}
+
+/// The longest screen, with every value at its longest.
+const SCREEN_LEN: usize = "Dur: ms\n".len()
+    + U64_LEN
+    + "CPM:\n".len()
+    + f32_len(2)
+    + "RD: uSv/h\n".len()
+    + f32_len(5)
+    + "Eff: b\n".len()
+    + f32_len(2)
+    + U64_LEN
+    + entropy::State::MAX_LEN
+    + "\n".len()
+    + "Hmin:\n".len()
+    + f32_len(2);
 
 #[embassy_executor::task]
 pub(crate) async fn run(
@@ -26,6 +50,7 @@ pub(crate) async fn run(
     dc: Peri<'static, PA1>,
     cs: Peri<'static, PA4>,
     mut geiger_subscriber: DynSubscriber<'static, geiger::count::Message>,
+    mut entropy_status: DynReceiver<'static, entropy::Status>,
 ) {
     let mut rst = gpio::Output::new(rst, gpio::Level::Low, gpio::Speed::Low);
     let dc = gpio::Output::new(dc, gpio::Level::Low, gpio::Speed::Low);
@@ -42,7 +67,7 @@ pub(crate) async fn run(
         .reset(&mut rst, &mut embassy_time::Delay)
         .await
         .unwrap();
-    if let Err(err) = try_display(&mut display, &mut geiger_subscriber).await {
+    if let Err(err) = try_display(&mut display, &mut geiger_subscriber, &mut entropy_status).await {
         defmt::error!("Failed to drive display: {:?}", defmt::Debug2Format(&err));
     }
 }
@@ -55,17 +80,39 @@ async fn try_display(
         TerminalModeAsync,
     >,
     geiger_subscriber: &mut DynSubscriber<'static, geiger::count::Message>,
+    entropy_status: &mut DynReceiver<'static, entropy::Status>,
 ) -> Result<(), TerminalModeError> {
     display.init().await?;
     display.clear().await?;
     display.write_str("hello, world").await?;
-    let mut line = heapless::String::<64>::new();
+    let mut line = heapless::String::<SCREEN_LEN>::new();
     loop {
-        let geiger::count::Message { dur, cpm, val } = geiger_subscriber.next_message_pure().await;
-        if write!(&mut line, "Dur:{dur} ms\nCPM:{cpm}\nRD:{val:.5} uSv/h\n").is_ok() {
+        let geiger::count::Message { dur, cpm, val, .. } =
+            geiger_subscriber.next_message_pure().await;
+        line.clear();
+        let mut result = write!(&mut line, "Dur:{dur} ms\nCPM:{cpm:.2}\nRD:{val:.5} uSv/h\n");
+        if let Some(entropy::Status {
+            state,
+            debias,
+            estimate,
+            ..
+        }) = entropy_status.try_get()
+        {
+            result = result.and_then(|_| {
+                write!(
+                    &mut line,
+                    "Eff:{:.2} {}b\n{state}\n",
+                    debias.efficiency(),
+                    debias.bits_out
+                )
+            });
+            if let Some(estimate) = estimate {
+                result = result.and_then(|_| write!(&mut line, "Hmin:{:.2}\n", estimate.min()));
+            }
+        }
+        if result.is_ok() {
             display.clear().await?;
             display.write_str(&line).await?;
-            line.clear();
         }
     }
 }
@@ -0,0 +1,109 @@
+//! Fortuna-style entropy accumulator (Ferguson and Schneier, chapter 9).
+//!
+//! Every source spreads its events round-robin over [`POOLS`] pools. Pool `i`
+//! only takes part in every 2^i-th reseed, so even if a weak or compromised
+//! source dominates the frequent reseeds, the rarely used pools eventually
+//! gather enough entropy from the others to recover the generator.
+//!
+//! A new source only needs a [`Source`] variant and calls to
+//! [`Output::add_event`](super::Output::add_event).
+
+use core::mem::MaybeUninit;
+
+use embassy_time::{Duration, Instant};
+use sha2::{Digest, Sha256};
+
+pub(crate) const POOLS: usize = 32;
+/// Largest event payload, as in Fortuna.
+pub(crate) const MAX_EVENT_BYTES: usize = 32;
+/// Bytes pool 0 must have collected before a reseed.
+const MIN_POOL_SIZE: usize = 64;
+/// Fortuna limits reseeds to ten per second.
+const MIN_RESEED_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum Source {
+    /// Debiased interval bits and unused conditioned blocks.
+    Geiger,
+    /// LSB noise of the boost converter feedback ADC.
+    BoostAdc,
+    /// Power-up state of uninitialized SRAM.
+    Sram,
+}
+
+impl Source {
+    const COUNT: usize = 3;
+}
+
+const SRAM_NOISE_BYTES: usize = 256;
+
+/// Left alone by the runtime, so it keeps the SRAM power-up state. After a
+/// warm reset it holds stale data instead, which does no harm in a pool.
+#[link_section = ".uninit.SRAM_NOISE"]
+static mut SRAM_NOISE: MaybeUninit<[u8; SRAM_NOISE_BYTES]> = MaybeUninit::uninit();
+
+/// Reads the boot-time contents of the uninitialized SRAM region.
+pub(crate) fn sram_noise() -> [u8; SRAM_NOISE_BYTES] {
+    let base = unsafe { core::ptr::addr_of!(SRAM_NOISE) } as *const u8;
+    core::array::from_fn(|i| unsafe { base.add(i).read_volatile() })
+}
+
+pub(crate) struct Accumulator {
+    pools: [Sha256; POOLS],
+    pool0_bytes: usize,
+    next_pool: [u8; Source::COUNT],
+    reseeds: u32,
+    last_reseed: Option<Instant>,
+}
+
+impl Accumulator {
+    pub(crate) fn new() -> Self {
+        Self {
+            pools: core::array::from_fn(|_| Sha256::new()),
+            pool0_bytes: 0,
+            next_pool: [0; Source::COUNT],
+            reseeds: 0,
+            last_reseed: None,
+        }
+    }
+
+    /// Reseeds taken from the pools since boot.
+    pub(crate) fn reseeds(&self) -> u32 {
+        self.reseeds
+    }
+
+    /// Adds one event of 1 to [`MAX_EVENT_BYTES`] bytes from `source`.
+    pub(crate) fn add(&mut self, source: Source, data: &[u8]) {
+        let data = &data[..data.len().min(MAX_EVENT_BYTES)];
+        let pool = &mut self.next_pool[source as usize];
+        self.pools[*pool as usize].update([source as u8, data.len() as u8]);
+        self.pools[*pool as usize].update(data);
+        if *pool == 0 {
+            self.pool0_bytes += 2 + data.len();
+        }
+        *pool = (*pool + 1) % POOLS as u8;
+    }
+
+    /// Returns the seed material for the next reseed, if one is due.
+    pub(crate) fn reseed(&mut self, now: Instant) -> Option<[u8; 32]> {
+        if self.pool0_bytes < MIN_POOL_SIZE
+            || self
+                .last_reseed
+                .is_some_and(|last| now.saturating_duration_since(last) < MIN_RESEED_INTERVAL)
+        {
+            return None;
+        }
+        self.reseeds = self.reseeds.wrapping_add(1);
+        self.pool0_bytes = 0;
+        self.last_reseed = Some(now);
+
+        let mut seed = Sha256::new();
+        for (i, pool) in self.pools.iter_mut().enumerate() {
+            if self.reseeds % (1 << i) != 0 {
+                break;
+            }
+            seed.update(Sha256::digest(pool.finalize_reset()));
+        }
+        Some(seed.finalize().into())
+    }
+}
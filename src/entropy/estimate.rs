@@ -0,0 +1,172 @@
+//! Running min-entropy estimates from NIST SP 800-90B section 6.3.
+//!
+//! The collision and Markov estimators are only defined for binary samples,
+//! so all three run on the raw interval comparison bits, before debiasing,
+//! over a sliding window of the latest [`WINDOW_BITS`] bits. The window is far
+//! shorter than a 90B assessment needs, the point is to notice a degrading
+//! tube or a noisy supply before the health tests fail hard.
+
+/// Size of the sliding window.
+pub(crate) const WINDOW_BITS: usize = 1024;
+/// Bits needed before the first estimate.
+const MIN_BITS: usize = 256;
+/// Upper bound of the 99% confidence interval of a normal distribution.
+const Z_99: f32 = 2.576;
+/// Sequence length the Markov estimate is computed over.
+const MARKOV_BITS: i32 = 128;
+
+/// Min-entropy estimates, in bits per comparison bit.
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct Estimates {
+    pub(crate) most_common_value: f32,
+    pub(crate) collision: f32,
+    pub(crate) markov: f32,
+}
+
+impl Estimates {
+    /// The assessed min-entropy, the lowest of all estimates.
+    pub(crate) fn min(&self) -> f32 {
+        self.most_common_value.min(self.collision).min(self.markov)
+    }
+}
+
+pub(crate) struct Estimator {
+    window: [u32; WINDOW_BITS / 32],
+    /// Index of the oldest bit.
+    start: usize,
+    len: usize,
+}
+
+impl Estimator {
+    pub(crate) const fn new() -> Self {
+        Self {
+            window: [0; WINDOW_BITS / 32],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, bit: bool) {
+        let index = if self.len < WINDOW_BITS {
+            self.len += 1;
+            (self.start + self.len - 1) % WINDOW_BITS
+        } else {
+            let index = self.start;
+            self.start = (self.start + 1) % WINDOW_BITS;
+            index
+        };
+        let (word, shift) = (index / 32, index % 32);
+        self.window[word] = self.window[word] & !(1 << shift) | (bit as u32) << shift;
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        let index = (self.start + i) % WINDOW_BITS;
+        self.window[index / 32] >> (index % 32) & 1 == 1
+    }
+
+    /// The bits in the window, oldest first.
+    pub(crate) fn bits(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.bit(i))
+    }
+
+    /// Estimates over the current window, once it holds enough bits.
+    pub(crate) fn estimate(&self) -> Option<Estimates> {
+        if self.len < MIN_BITS {
+            return None;
+        }
+        Some(Estimates {
+            most_common_value: self.most_common_value(),
+            collision: self.collision(),
+            markov: self.markov(),
+        })
+    }
+
+    /// The most common value estimate, section 6.3.1.
+    fn most_common_value(&self) -> f32 {
+        let len = self.len as f32;
+        let ones = self.bits().filter(|&bit| bit).count() as f32;
+        let p = ones.max(len - ones) / len;
+        let p_upper = (p + Z_99 * libm::sqrtf(p * (1. - p) / (len - 1.))).min(1.);
+        libm::log2f(1. / p_upper)
+    }
+
+    /// The collision estimate, section 6.3.2.
+    fn collision(&self) -> f32 {
+        // Binary samples always collide within two or three samples.
+        let (mut count, mut sum, mut sum_squares) = (0u32, 0u32, 0u32);
+        let mut i = 0;
+        while i + 1 < self.len {
+            let t = if self.bit(i) == self.bit(i + 1) {
+                2
+            } else if i + 2 < self.len {
+                3
+            } else {
+                break;
+            };
+            count += 1;
+            sum += t;
+            sum_squares += t * t;
+            i += t as usize;
+        }
+        if count < 2 {
+            return 1.;
+        }
+        let count = count as f32;
+        let mean = sum as f32 / count;
+        let variance = (sum_squares as f32 - count * mean * mean) / (count - 1.);
+        let mean_lower = mean - Z_99 * libm::sqrtf(variance.max(0.)) / libm::sqrtf(count);
+        // For binary samples the expected collision time is 2 + 2p(1 - p),
+        // which replaces the binary search of the general case.
+        let pq = (mean_lower - 2.) / 2.;
+        if pq >= 0.25 {
+            return 1.;
+        }
+        let p = (1. + libm::sqrtf(1. - 4. * pq.max(0.))) / 2.;
+        libm::log2f(1. / p)
+    }
+
+    /// The Markov estimate, section 6.3.3.
+    fn markov(&self) -> f32 {
+        let mut transitions = [[0u32; 2]; 2];
+        let mut zeros = 0u32;
+        let mut previous = None;
+        for bit in self.bits() {
+            zeros += !bit as u32;
+            if let Some(previous) = previous {
+                transitions[previous as usize][bit as usize] += 1;
+            }
+            previous = Some(bit);
+        }
+        let p0 = zeros as f32 / self.len as f32;
+        let p1 = 1. - p0;
+        let [p00, p01, p10, p11] = {
+            let ratio = |from: usize, to: usize| {
+                let total = transitions[from][0] + transitions[from][1];
+                if total == 0 {
+                    0.
+                } else {
+                    transitions[from][to] as f32 / total as f32
+                }
+            };
+            [ratio(0, 0), ratio(0, 1), ratio(1, 0), ratio(1, 1)]
+        };
+
+        // The most likely 128-bit sequences, computed in log2 to avoid
+        // underflow.
+        let log2 = |p: f32| libm::log2f(p);
+        let n = MARKOV_BITS as f32;
+        let candidates = [
+            log2(p0) + (n - 1.) * log2(p00),
+            log2(p0) + (n / 2.) * log2(p01) + (n / 2. - 1.) * log2(p10),
+            log2(p0) + log2(p01) + (n - 2.) * log2(p11),
+            log2(p1) + log2(p10) + (n - 2.) * log2(p00),
+            log2(p1) + (n / 2.) * log2(p10) + (n / 2. - 1.) * log2(p01),
+            log2(p1) + (n - 1.) * log2(p11),
+        ];
+        let log2_p_max = candidates
+            .into_iter()
+            .filter(|p| !p.is_nan())
+            .fold(f32::NEG_INFINITY, f32::max);
+        ((0. - log2_p_max) / n).min(1.)
+    }
+}
@@ -0,0 +1,132 @@
+//! Continuous health tests from NIST SP 800-90B section 4.4.
+//!
+//! Both tests run on every digitized sample, the interval comparison bit
+//! that is credited to the conditioner, with a false positive probability of
+//! 2^-20 per sample. The cutoffs are derived from the min-entropy per bit
+//! assessed during the startup self-test.
+
+/// False positive probability of each test, as a power of two.
+const ALPHA_LOG2: f64 = -20.;
+/// Adaptive Proportion Test window size for binary samples.
+const APT_WINDOW: u32 = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum Failure {
+    RepetitionCount,
+    AdaptiveProportion,
+}
+
+impl Failure {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Failure::RepetitionCount => "RCT",
+            Failure::AdaptiveProportion => "APT",
+        }
+    }
+}
+
+pub(crate) struct HealthTests {
+    rct: RepetitionCount,
+    apt: AdaptiveProportion,
+}
+
+impl HealthTests {
+    /// Creates the tests for a noise source with `min_entropy` bits per bit.
+    pub(crate) fn new(min_entropy: f32) -> Self {
+        let rct = RepetitionCount::new(min_entropy);
+        let apt = AdaptiveProportion::new(min_entropy);
+        defmt::info!(
+            "Health test cutoffs: RCT {}, APT {}/{}",
+            rct.cutoff,
+            apt.cutoff,
+            APT_WINDOW
+        );
+        Self { rct, apt }
+    }
+
+    pub(crate) fn push(&mut self, sample: bool) -> Result<(), Failure> {
+        let rct = self.rct.push(sample);
+        let apt = self.apt.push(sample);
+        rct.and(apt)
+    }
+}
+
+struct RepetitionCount {
+    cutoff: u32,
+    last: Option<bool>,
+    count: u32,
+}
+
+impl RepetitionCount {
+    fn new(min_entropy: f32) -> Self {
+        Self {
+            cutoff: 1 + libm::ceil(-ALPHA_LOG2 / min_entropy as f64) as u32,
+            last: None,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, sample: bool) -> Result<(), Failure> {
+        if self.last == Some(sample) {
+            self.count += 1;
+        } else {
+            self.last = Some(sample);
+            self.count = 1;
+        }
+        if self.count >= self.cutoff {
+            return Err(Failure::RepetitionCount);
+        }
+        Ok(())
+    }
+}
+
+struct AdaptiveProportion {
+    cutoff: u32,
+    first: bool,
+    seen: u32,
+    count: u32,
+}
+
+impl AdaptiveProportion {
+    fn new(min_entropy: f32) -> Self {
+        let p = libm::exp2(-min_entropy as f64);
+        Self {
+            cutoff: 1 + critbinom(APT_WINDOW, p, 1. - libm::exp2(ALPHA_LOG2)),
+            first: false,
+            seen: APT_WINDOW,
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, sample: bool) -> Result<(), Failure> {
+        if self.seen == APT_WINDOW {
+            self.first = sample;
+            self.seen = 1;
+            self.count = 1;
+            return Ok(());
+        }
+        self.seen += 1;
+        if sample == self.first {
+            self.count += 1;
+            if self.count >= self.cutoff {
+                return Err(Failure::AdaptiveProportion);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Smallest `k` for which the binomial CDF of `n` trials with success
+/// probability `p` reaches `probability`, like Excel's CRITBINOM.
+fn critbinom(n: u32, p: f64, probability: f64) -> u32 {
+    // Walking the PMF up from zero keeps this free of factorials.
+    let mut pmf = libm::pow(1. - p, n as f64);
+    let mut cdf = pmf;
+    let mut k = 0;
+    while cdf < probability && k < n {
+        pmf *= (n - k) as f64 / (k + 1) as f64 * p / (1. - p);
+        cdf += pmf;
+        k += 1;
+    }
+    k
+}
@@ -0,0 +1,261 @@
+pub(crate) mod accumulator;
+pub(crate) mod estimate;
+pub(crate) mod health;
+pub(crate) mod output;
+pub(crate) mod startup;
+
+pub(crate) use banana_entropy::{condition, debias, drbg};
+pub(crate) use output::Output;
+
+use defmt::{debug, error, info};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex,
+    mutex::Mutex,
+    pubsub::{DynPublisher, DynSubscriber},
+    watch::{DynReceiver, DynSender},
+};
+use sequential_storage::cache::NoCache;
+
+use crate::{geiger, settings::Settings, storage};
+
+type Storage = Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>;
+
+/// One byte of debiased interval comparison bits.
+///
+/// These are not full entropy; use [`Output`] for random data.
+#[derive(Clone)]
+pub(crate) struct Message(pub(crate) u8);
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum State {
+    /// Running the startup self-test, `samples` of [`startup::SAMPLES`]
+    /// comparison bits collected.
+    WarmingUp {
+        samples: u32,
+    },
+    SelfTestFailed(startup::Failure),
+    Running,
+    /// A continuous health test failed, output is stopped until reboot.
+    HealthTestFailed(health::Failure),
+}
+
+impl State {
+    /// Longest [`Display`](core::fmt::Display) output, warming up with both
+    /// counts at their longest.
+    pub(crate) const MAX_LEN: usize = "warming up 4294967295/4294967295".len();
+}
+
+impl core::fmt::Display for State {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            State::WarmingUp { samples } => {
+                write!(f, "warming up {samples}/{}", startup::SAMPLES)
+            }
+            State::SelfTestFailed(failure) => write!(f, "self-test failed ({})", failure.name()),
+            State::Running => write!(f, "running"),
+            State::HealthTestFailed(failure) => {
+                write!(f, "health test failed ({})", failure.name())
+            }
+        }
+    }
+}
+
+/// State of the entropy pipeline, for the display and USB status reports.
+#[derive(Clone)]
+pub(crate) struct Status {
+    pub(crate) state: State,
+    pub(crate) debias: debias::Stats,
+    pub(crate) condition: condition::Stats,
+    pub(crate) drbg: output::Stats,
+    /// Min-entropy of the raw comparison bits, once the window is filled.
+    pub(crate) estimate: Option<estimate::Estimates>,
+    /// Health test failures since the counter was last reset, persisted in
+    /// storage.
+    pub(crate) health_failures: u32,
+}
+
+#[embassy_executor::task]
+pub(crate) async fn run(
+    mut geiger_subscriber: DynSubscriber<'static, geiger::count::Message>,
+    publisher: DynPublisher<'static, Message>,
+    status: DynSender<'static, Status>,
+    output: &'static Output,
+    storage: &'static Storage,
+    mut settings: DynReceiver<'static, Settings>,
+) {
+    // The health tests and the min-entropy credited per bit, from the
+    // startup assessment.
+    let mut running = None;
+    let mut comparator = extract::IntervalComparator::new();
+    let mut estimator = estimate::Estimator::new();
+    let mut debiaser = debias::Debiaser::new(settings.get().await.debias);
+    let mut packer = extract::BytePacker::new();
+    let mut conditioner = condition::Conditioner::new();
+    let mut health_failures = storage
+        .lock()
+        .await
+        .read(storage::keys::HEALTH_FAILURES)
+        .await
+        .unwrap_or(None)
+        .unwrap_or(0u32);
+    for chunk in accumulator::sram_noise().chunks(accumulator::MAX_EVENT_BYTES) {
+        output.add_event(accumulator::Source::Sram, chunk).await;
+    }
+    let mut state = match startup::known_answer_tests() {
+        Ok(()) => State::WarmingUp { samples: 0 },
+        Err(failure) => {
+            error!("Known-answer test failed: {}", failure);
+            output.stop(State::SelfTestFailed(failure));
+            State::SelfTestFailed(failure)
+        }
+    };
+    loop {
+        status.send(Status {
+            state,
+            debias: debiaser.stats(),
+            condition: conditioner.stats(),
+            drbg: output.stats().await,
+            estimate: estimator.estimate(),
+            health_failures,
+        });
+
+        let geiger::count::Message { ticks, .. } = geiger_subscriber.next_message_pure().await;
+        if let Some(Settings { debias, .. }) = settings.try_changed() {
+            if debias != debiaser.stats().method {
+                info!("Debias method: {}", debias.name());
+                debiaser.set_method(debias);
+            }
+        }
+        // The raw intervals are hashed too, without credit.
+        conditioner.absorb(&ticks.to_le_bytes(), 0.);
+        // Only the comparison bits are tested and credited, ties give none.
+        let Some(bit) = comparator.push(ticks) else {
+            continue;
+        };
+        let failed = match state {
+            State::WarmingUp { samples } if samples + 1 < startup::SAMPLES => {
+                estimator.push(bit);
+                state = State::WarmingUp {
+                    samples: samples + 1,
+                };
+                false
+            }
+            State::WarmingUp { .. } => {
+                estimator.push(bit);
+                match startup::assess(&estimator) {
+                    Ok((min_entropy, health_tests)) => {
+                        info!(
+                            "Startup self-test passed, {} bits of min-entropy per bit, \
+                             random output enabled",
+                            min_entropy
+                        );
+                        running = Some((health_tests, min_entropy));
+                        state = State::Running;
+                        false
+                    }
+                    Err(failure) => {
+                        error!("Startup self-test failed: {}", failure);
+                        state = State::SelfTestFailed(failure);
+                        true
+                    }
+                }
+            }
+            State::Running => {
+                let Some((health_tests, min_entropy)) = running.as_mut() else {
+                    unreachable!("assessed before running");
+                };
+                match health_tests.push(bit) {
+                    Ok(()) => {
+                        estimator.push(bit);
+                        conditioner.absorb(&[bit as u8], *min_entropy);
+                        if let Some(block) = conditioner.squeeze() {
+                            output.feed(block).await;
+                        }
+                        let mut bytes = heapless::Vec::<u8, 8>::new();
+                        debiaser.push(bit, |bit| {
+                            if let Some(byte) = packer.push(bit) {
+                                debug!("entropy: {:02x}", byte);
+                                publisher.publish_immediate(Message(byte));
+                                let _ = bytes.push(byte);
+                            }
+                        });
+                        if !bytes.is_empty() {
+                            output.add_event(accumulator::Source::Geiger, &bytes).await;
+                        }
+                        false
+                    }
+                    Err(failure) => {
+                        error!("Health test failed: {}, random output stopped", failure);
+                        state = State::HealthTestFailed(failure);
+                        true
+                    }
+                }
+            }
+            State::SelfTestFailed(_) | State::HealthTestFailed(_) => false,
+        };
+
+        if failed {
+            output.stop(state);
+            health_failures += 1;
+            if let Err(e) = storage
+                .lock()
+                .await
+                .write(storage::keys::HEALTH_FAILURES, &health_failures)
+                .await
+            {
+                error!("Failed to store health test failures: {:?}", e);
+            }
+        }
+    }
+}
+
+pub(crate) mod extract {
+    /// Turns pairs of inter-arrival intervals into bits.
+    ///
+    /// Intervals are consumed in non-overlapping pairs (t1, t2), so every
+    /// interval contributes to at most one bit: t1 < t2 gives 0, t1 > t2
+    /// gives 1 and ties are discarded.
+    pub(crate) struct IntervalComparator {
+        first: Option<u64>,
+    }
+
+    impl IntervalComparator {
+        pub(crate) const fn new() -> Self {
+            Self { first: None }
+        }
+
+        pub(crate) fn push(&mut self, interval: u64) -> Option<bool> {
+            match self.first.take() {
+                None => {
+                    self.first = Some(interval);
+                    None
+                }
+                Some(first) if first == interval => None,
+                Some(first) => Some(first > interval),
+            }
+        }
+    }
+
+    /// Collects bits, MSB first, into bytes.
+    pub(crate) struct BytePacker {
+        byte: u8,
+        len: u8,
+    }
+
+    impl BytePacker {
+        pub(crate) const fn new() -> Self {
+            Self { byte: 0, len: 0 }
+        }
+
+        pub(crate) fn push(&mut self, bit: bool) -> Option<u8> {
+            self.byte = (self.byte << 1) | bit as u8;
+            self.len += 1;
+            if self.len < 8 {
+                return None;
+            }
+            let byte = self.byte;
+            *self = Self::new();
+            Some(byte)
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Random output shared by every interface.
+//!
+//! There are two kinds of output, kept apart on purpose:
+//!
+//! - [`Output::read_true`] returns full-entropy blocks straight from the
+//!   conditioning component. It is slow, about one block per hour at
+//!   background level.
+//! - [`Output::read_drbg`] returns HMAC_DRBG output. The DRBG is instantiated
+//!   from conditioned blocks and then reseeded from the Fortuna-style
+//!   [`accumulator`](super::accumulator), which mixes in every source.
+//!
+//! Every conditioned block is used exactly once, either as true entropy or as
+//! DRBG seed material, so true entropy output never reveals a DRBG seed.
+//!
+//! Both stop for good once a self-test or health test fails.
+
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    channel::{Channel, TrySendError},
+    mutex::Mutex,
+};
+use embassy_time::{Instant, Timer};
+
+use super::{
+    accumulator::{Accumulator, Source},
+    condition::OUTPUT_BYTES,
+    drbg, State,
+};
+
+pub(crate) type Block = [u8; OUTPUT_BYTES];
+
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct Stats {
+    pub(crate) seeded: bool,
+    /// Generate requests since the last reseed.
+    pub(crate) reseed_counter: u64,
+    pub(crate) reseeds: u32,
+}
+
+/// Random output is stopped until reboot, a test failed with the state.
+#[derive(Clone, Copy, defmt::Format)]
+pub(crate) struct Stopped(pub(crate) State);
+
+impl core::fmt::Display for Stopped {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "random output stopped, {}", self.0)
+    }
+}
+
+struct Drbg {
+    drbg: Option<drbg::HmacDrbg>,
+    /// Entropy input kept until the nonce block for instantiation arrives.
+    pending: Option<Block>,
+    accumulator: Accumulator,
+}
+
+pub(crate) struct Output {
+    blocks: Channel<ThreadModeRawMutex, Block, 4>,
+    drbg: Mutex<ThreadModeRawMutex, Drbg>,
+    /// The failed state, once random output is stopped.
+    stopped: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<Option<State>>>,
+}
+
+impl Output {
+    pub(crate) fn new() -> Self {
+        Self {
+            blocks: Channel::new(),
+            drbg: Mutex::new(Drbg {
+                drbg: None,
+                pending: None,
+                accumulator: Accumulator::new(),
+            }),
+            stopped: blocking_mutex::Mutex::new(Cell::new(None)),
+        }
+    }
+
+    pub(crate) async fn stats(&self) -> Stats {
+        let drbg = self.drbg.lock().await;
+        Stats {
+            seeded: drbg.drbg.is_some(),
+            reseed_counter: drbg.drbg.as_ref().map_or(0, |d| d.reseed_counter()),
+            reseeds: drbg.accumulator.reseeds(),
+        }
+    }
+
+    /// Stops all random output until reboot, after a test failed with
+    /// `state`. Conditioned blocks not read yet are dropped.
+    pub(super) fn stop(&self, state: State) {
+        self.stopped.lock(|stopped| stopped.set(Some(state)));
+        self.blocks.clear();
+    }
+
+    /// Fails once a test stopped random output.
+    pub(crate) fn stopped(&self) -> Result<(), Stopped> {
+        match self.stopped.lock(Cell::get) {
+            Some(state) => Err(Stopped(state)),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds an entropy event from `source` to the accumulator.
+    pub(crate) async fn add_event(&self, source: Source, data: &[u8]) {
+        self.drbg.lock().await.accumulator.add(source, data);
+    }
+
+    /// Hands over a new conditioned block.
+    pub(super) async fn feed(&self, block: Block) {
+        let mut drbg = self.drbg.lock().await;
+        let Drbg {
+            drbg: slot,
+            pending,
+            accumulator,
+        } = &mut *drbg;
+
+        if slot.is_none() {
+            // The first two blocks instantiate the DRBG: one full-entropy
+            // block as entropy input, half of the next as the nonce. The
+            // device UID makes the personalization string.
+            match pending.take() {
+                None => *pending = Some(block),
+                Some(entropy) => {
+                    let nonce = &block[..OUTPUT_BYTES / 2];
+                    *slot = Some(drbg::HmacDrbg::new(
+                        &entropy,
+                        nonce,
+                        embassy_stm32::uid::uid(),
+                    ));
+                    defmt::info!("DRBG instantiated");
+                }
+            }
+        } else if let Err(TrySendError::Full(block)) = self.blocks.try_send(block) {
+            // Nobody is reading true entropy, keep the block for the DRBG.
+            accumulator.add(Source::Geiger, &block);
+        }
+    }
+
+    /// Waits for the next block of full-entropy bytes. Fails with
+    /// [`Stopped`] once the output is stopped, a read already
+    /// waiting then gets no more blocks.
+    ///
+    /// Cancel safe: a block only leaves the channel when it is returned, so
+    /// dropping the future never loses one.
+    pub(crate) async fn read_true(&self) -> Result<Block, Stopped> {
+        self.stopped()?;
+        Ok(self.blocks.receive().await)
+    }
+
+    /// Fills `buf` with DRBG output, waiting for the DRBG to be seeded.
+    /// Fails with [`Stopped`] once the output is stopped, `buf`
+    /// is then only partly filled.
+    ///
+    /// With `prediction_resistance` the DRBG is first reseeded with a fresh
+    /// conditioned block, which can take minutes to arrive.
+    pub(crate) async fn read_drbg(
+        &self,
+        buf: &mut [u8],
+        prediction_resistance: bool,
+    ) -> Result<(), Stopped> {
+        let mut reseed = prediction_resistance;
+        let mut entropy = None;
+        let mut chunks = buf.chunks_mut(drbg::MAX_REQUEST_BYTES);
+        let mut chunk = chunks.next();
+        while let Some(output) = chunk.as_deref_mut() {
+            self.stopped()?;
+            if reseed && entropy.is_none() {
+                entropy = Some(self.read_true().await?);
+            }
+            let mut drbg = self.drbg.lock().await;
+            let Drbg {
+                drbg: slot,
+                accumulator,
+                ..
+            } = &mut *drbg;
+            let Some(generator) = slot.as_mut() else {
+                // Seeding only happens once after boot, polling is enough.
+                drop(drbg);
+                Timer::after_millis(100).await;
+                continue;
+            };
+            if let Some(seed) = accumulator.reseed(Instant::now()) {
+                generator.reseed(&seed, &[]);
+            }
+            if let Some(entropy) = entropy.take() {
+                generator.reseed(&entropy, &[]);
+            }
+            match generator.generate(output, &[]) {
+                Ok(()) => {
+                    reseed = false;
+                    chunk = chunks.next();
+                }
+                Err(drbg::Error::ReseedRequired) => reseed = true,
+                Err(drbg::Error::RequestTooLarge) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Startup self-test gating the random output.
+//!
+//! After boot the known-answer tests of the deterministic stages run once.
+//! Then the first [`SAMPLES`] interval comparison bits fill the estimator
+//! window, the min-entropy per bit is assessed from them, and they go through
+//! the health tests with cutoffs derived from that assessment before they
+//! are discarded. Random data is only published once all of this has passed.
+
+use super::{condition, debias, drbg, estimate, health};
+
+/// Comparison bits the health tests must pass before output is enabled,
+/// also the bits the min-entropy is assessed from.
+pub(crate) const SAMPLES: u32 = estimate::WINDOW_BITS as u32;
+/// Lowest assessed min-entropy per bit accepted, far below what a working
+/// tube gives.
+pub(crate) const MIN_ENTROPY: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum Failure {
+    KnownAnswer(&'static str),
+    /// The assessed min-entropy is below [`MIN_ENTROPY`].
+    LowEntropy,
+    Health(health::Failure),
+}
+
+impl Failure {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Failure::KnownAnswer(name) => name,
+            Failure::LowEntropy => "min-entropy",
+            Failure::Health(failure) => failure.name(),
+        }
+    }
+}
+
+/// Runs the known-answer tests of every deterministic processing stage.
+pub(crate) fn known_answer_tests() -> Result<(), Failure> {
+    if !debias::self_test() {
+        return Err(Failure::KnownAnswer("debias"));
+    }
+    if !condition::self_test() {
+        return Err(Failure::KnownAnswer("sha256"));
+    }
+    if !drbg::self_test() {
+        return Err(Failure::KnownAnswer("hmac-drbg"));
+    }
+    Ok(())
+}
+
+/// Assesses the min-entropy per bit over the full estimator window and runs
+/// the health tests on the same bits. Returns the assessment and the tests,
+/// which keep running on every later bit.
+pub(crate) fn assess(
+    estimator: &estimate::Estimator,
+) -> Result<(f32, health::HealthTests), Failure> {
+    let min_entropy = estimator.estimate().map_or(0., |estimate| estimate.min());
+    if min_entropy.is_nan() || min_entropy < MIN_ENTROPY {
+        return Err(Failure::LowEntropy);
+    }
+    let mut health_tests = health::HealthTests::new(min_entropy);
+    for bit in estimator.bits() {
+        health_tests.push(bit).map_err(Failure::Health)?;
+    }
+    Ok((min_entropy, health_tests))
+}
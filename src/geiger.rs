@@ -1,29 +1,27 @@
+use core::cell::Cell;
+
 use defmt::info;
 use embassy_futures::join::join;
 use embassy_stm32::{
     adc::{Adc, SampleTime, VREF_INT},
-    exti::ExtiInput,
-    gpio::{OutputType, Pull},
-    peripherals::{ADC1, EXTI8, PB0, PB8, PB9, TIM4},
-    time::Hertz,
-    timer::{
-        low_level::CountingMode,
-        simple_pwm::{PwmPin, SimplePwm},
-    },
+    peripherals::{ADC1, PB0, PB8, PB9, TIM4},
     Peri,
 };
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, pubsub::DynPublisher};
-use embassy_time::{Duration, Instant, Ticker};
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, pubsub::DynPublisher, watch::DynReceiver,
+};
+use embassy_time::{Duration, Ticker};
 use pid::Pid;
 use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
 use sequential_storage::cache::NoCache;
 
-use crate::storage;
+use crate::{entropy, settings::Settings, storage};
 
 type Storage = Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>;
 
-const GEIGER_BACKGROUND_LEVEL: f32 = 25. / 60.; // 盖革管本底脉冲数 pulses/sec
-const GEIGER_SENSITIVITY: f32 = 44.; // 盖革管灵敏度 CPS at 1 mR/h Co-60
+pub(crate) const GEIGER_BACKGROUND_LEVEL: f32 = 25. / 60.; // 盖革管本底脉冲数 pulses/sec
+pub(crate) const GEIGER_SENSITIVITY: f32 = 44.; // 盖革管灵敏度 CPS at 1 mR/h Co-60
+pub(crate) const BOOST_VOLTS: f32 = 380.; // 盖革管工作电压 V
 const BED: f32 = 0.0778; // 香蕉等效剂量 1 Banana Equivalent Dose = 0.0778 µSv
 
 #[embassy_executor::task]
@@ -33,41 +31,195 @@ pub(crate) async fn run(
     boost_pwm_pin: Peri<'static, PB9>,
     boost_pwm_tim: Peri<'static, TIM4>,
     geiger_output_pin: Peri<'static, PB8>,
-    geiger_output_exti: Peri<'static, EXTI8>,
     publisher: DynPublisher<'static, count::Message>,
     storage: &'static Storage,
+    entropy_output: &'static entropy::Output,
+    boost_settings: DynReceiver<'static, Settings>,
+    count_settings: DynReceiver<'static, Settings>,
 ) {
+    let timer = timer::SharedTimer::new(boost_pwm_tim, boost_pwm_pin, geiger_output_pin);
+    let boost_volts = Cell::new(f32::NAN);
     join(
-        boost::run(adc, boost_fb_pin, boost_pwm_pin, boost_pwm_tim),
-        count::run(geiger_output_pin, geiger_output_exti, publisher, storage),
+        boost::run(
+            adc,
+            boost_fb_pin,
+            &timer,
+            &boost_volts,
+            entropy_output,
+            boost_settings,
+        ),
+        count::run(&timer, &boost_volts, publisher, storage, count_settings),
     )
     .await;
 }
 
+mod timer {
+    //! TIM4 is shared by the boost converter PWM (CH4 on PB9) and the Geiger
+    //! pulse input capture (CH3 on PB8).
+    //!
+    //! The timer runs at the full 72 MHz timer clock and the capture unit
+    //! latches the counter on the falling edge in hardware, so interrupt
+    //! latency no longer shows up in the timestamps. The 16-bit capture value
+    //! is extended with a software count of update events, which needs the
+    //! interrupt anyway: a DMA-only capture could not tell how many PWM
+    //! periods passed between two pulses, and the TIM4_CH3 request shares
+    //! DMA1_CH5 with the USART1 RX of the UART bridge.
+
+    use core::cell::Cell;
+
+    use embassy_stm32::{
+        gpio::{OutputType, Pull},
+        interrupt,
+        interrupt::InterruptExt,
+        pac,
+        peripherals::{PB8, PB9, TIM4},
+        time::Hertz,
+        timer::{
+            input_capture::CapturePin,
+            low_level::{
+                CountingMode, FilterValue, InputCaptureMode, InputTISelection, OutputCompareMode,
+                Timer,
+            },
+            simple_pwm::PwmPin,
+            Ch3, Ch4, Channel,
+        },
+        Peri,
+    };
+    use embassy_sync::{
+        blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+        channel::Channel as Queue,
+    };
+    use embassy_time::Duration;
+
+    /// Timer clock, APB1 timer clock with the RCC configuration in `main`.
+    pub(super) const TICK_HZ: u64 = 72_000_000;
+    const PWM_FREQ: Hertz = Hertz::khz(5);
+    const CAPTURE_CHANNEL: Channel = Channel::Ch3;
+    const PWM_CHANNEL: Channel = Channel::Ch4;
+
+    /// Number of completed timer periods.
+    static OVERFLOWS: Mutex<CriticalSectionRawMutex, Cell<u64>> = Mutex::new(Cell::new(0));
+    /// Ring of raw pulse timestamps, filled by the capture interrupt.
+    static PULSES: Queue<CriticalSectionRawMutex, Instant, 16> = Queue::new();
+
+    /// A pulse timestamp in timer ticks since the timer was started.
+    #[derive(Clone, Copy)]
+    pub(super) struct Instant(u64);
+
+    impl Instant {
+        pub(super) const ZERO: Instant = Instant(0);
+
+        pub(super) fn ticks_since(&self, earlier: Instant) -> u64 {
+            self.0.saturating_sub(earlier.0)
+        }
+
+        pub(super) fn duration_since(&self, earlier: Instant) -> Duration {
+            Duration::from_micros(self.ticks_since(earlier) / (TICK_HZ / 1_000_000))
+        }
+    }
+
+    pub(super) struct SharedTimer {
+        timer: Timer<'static, TIM4>,
+        _pwm_pin: PwmPin<'static, TIM4, Ch4>,
+        _capture_pin: CapturePin<'static, TIM4, Ch3>,
+    }
+
+    impl SharedTimer {
+        pub(super) fn new(
+            tim: Peri<'static, TIM4>,
+            pwm_pin: Peri<'static, PB9>,
+            capture_pin: Peri<'static, PB8>,
+        ) -> Self {
+            let pwm_pin = PwmPin::new(pwm_pin, OutputType::PushPull);
+            let capture_pin = CapturePin::new(capture_pin, Pull::None);
+            let timer = Timer::new(tim);
+            defmt::assert_eq!(timer.get_clock_frequency().0 as u64, TICK_HZ);
+
+            timer.set_counting_mode(CountingMode::EdgeAlignedUp);
+            timer.set_frequency(PWM_FREQ);
+
+            timer.set_output_compare_mode(PWM_CHANNEL, OutputCompareMode::PwmMode1);
+            timer.set_output_compare_preload(PWM_CHANNEL, true);
+            timer.enable_channel(PWM_CHANNEL, true);
+
+            timer.set_input_capture_filter(CAPTURE_CHANNEL, FilterValue::FCK_INT_N8);
+            timer.set_input_ti_selection(CAPTURE_CHANNEL, InputTISelection::Normal);
+            timer.set_input_capture_prescaler(CAPTURE_CHANNEL, 0);
+            timer.set_input_capture_mode(CAPTURE_CHANNEL, InputCaptureMode::Falling);
+            timer.enable_channel(CAPTURE_CHANNEL, true);
+
+            timer.enable_update_interrupt(true);
+            timer.enable_input_interrupt(CAPTURE_CHANNEL, true);
+            interrupt::TIM4.unpend();
+            unsafe { interrupt::TIM4.enable() };
+            timer.start();
+
+            Self {
+                timer,
+                _pwm_pin: pwm_pin,
+                _capture_pin: capture_pin,
+            }
+        }
+
+        pub(super) fn max_duty_cycle(&self) -> u16 {
+            self.timer.regs_gp16().arr().read().arr() + 1
+        }
+
+        pub(super) fn set_duty_cycle(&self, duty: u16) {
+            self.timer
+                .regs_gp16()
+                .ccr(PWM_CHANNEL.index())
+                .modify(|w| w.set_ccr(duty));
+        }
+
+        /// Waits for the next Geiger pulse and returns its capture timestamp.
+        pub(super) async fn wait_for_pulse(&self) -> Instant {
+            PULSES.receive().await
+        }
+    }
+
+    #[interrupt]
+    fn TIM4() {
+        let regs = pac::TIM4;
+        let sr = regs.sr().read();
+        let period = regs.arr().read().arr() as u64 + 1;
+        let overflows = OVERFLOWS.lock(|o| o.get());
+
+        if sr.ccif(CAPTURE_CHANNEL.index()) {
+            let ccr = regs.ccr(CAPTURE_CHANNEL.index()).read().ccr() as u64;
+            // The counter wrapped after the capture latched but before this
+            // interrupt got to count it: a small capture value belongs to the
+            // next period.
+            let wrapped = sr.uif() && ccr < period / 2;
+            let instant = Instant((overflows + wrapped as u64) * period + ccr);
+            if PULSES.try_send(instant).is_err() {
+                defmt::warn!("Pulse timestamp ring is full, dropping a pulse");
+            }
+            regs.sr()
+                .modify(|w| w.set_ccif(CAPTURE_CHANNEL.index(), false));
+        }
+        if sr.uif() {
+            OVERFLOWS.lock(|o| o.set(overflows + 1));
+            regs.sr().modify(|w| w.set_uif(false));
+        }
+    }
+}
+
 mod boost {
     use super::*;
 
     pub(super) async fn run(
         mut adc: Adc<'static, ADC1>,
         mut boost_fb_pin: Peri<'static, PB0>,
-        boost_pwm_pin: Peri<'static, PB9>,
-        boost_pwm_tim: Peri<'static, TIM4>,
+        boost_pwm: &timer::SharedTimer,
+        measured: &Cell<f32>,
+        entropy_output: &entropy::Output,
+        mut settings: DynReceiver<'static, Settings>,
     ) {
-        let mut boost_pwm = SimplePwm::new(
-            boost_pwm_tim,
-            None,
-            None,
-            None,
-            Some(PwmPin::new(boost_pwm_pin, OutputType::PushPull)),
-            Hertz::khz(5),
-            CountingMode::EdgeAlignedUp,
-        );
-        let mut boost_pwm_channel = boost_pwm.ch4();
-        boost_pwm_channel.set_duty_cycle(boost_pwm_channel.max_duty_cycle() / 2);
-        boost_pwm_channel.enable();
+        boost_pwm.set_duty_cycle(boost_pwm.max_duty_cycle() / 2);
 
         let mut boost_duty = 0.5;
-        let mut pid = Pid::<f32>::new(380., 0.3);
+        let mut pid = Pid::<f32>::new(settings.get().await.boost_volts, 0.3);
         pid.p(0.0008, 0.1);
         pid.d(0.0001, 0.01);
 
@@ -77,14 +229,25 @@ mod boost {
         loop {
             let v = adc.read(&mut boost_fb_pin).await;
             let vrefint_sample = adc.read(&mut vrefint).await;
+            let [v0, v1] = v.to_le_bytes();
+            let [r0, r1] = vrefint_sample.to_le_bytes();
+            entropy_output
+                .add_event(entropy::accumulator::Source::BoostAdc, &[v0, v1, r0, r1])
+                .await;
             let sample_volt = sample_volt(v, vrefint_sample);
             let boost_volt = geiger_volt(sample_volt);
             info!("boost: {} V", boost_volt);
+            measured.set(boost_volt);
+
+            if let Some(Settings { boost_volts, .. }) = settings.try_changed() {
+                info!("boost setpoint: {} V", boost_volts);
+                pid.setpoint(boost_volts);
+            }
 
             let next = pid.next_control_output(boost_volt);
             boost_duty = (boost_duty + next.output).clamp(0.0, 0.9);
-            let max_duty = boost_pwm_channel.max_duty_cycle() as f32;
-            boost_pwm_channel.set_duty_cycle((max_duty * (1. - boost_duty)) as u16);
+            let max_duty = boost_pwm.max_duty_cycle() as f32;
+            boost_pwm.set_duty_cycle((max_duty * (1. - boost_duty)) as u16);
 
             ticker.next().await;
         }
@@ -104,36 +267,61 @@ mod boost {
 
 pub(crate) mod count {
     use defmt::error;
+    use embassy_sync::signal::Signal;
 
     use super::*;
 
+    static RESET: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+    /// Restarts the pulse count from zero at the next pulse.
+    pub(crate) fn reset() {
+        RESET.signal(());
+    }
+
     #[derive(Clone)]
     pub(crate) struct Message {
+        /// Time of the pulse since boot, in milliseconds.
+        pub(crate) time: u64,
         pub(crate) dur: u64,
+        /// Raw inter-arrival interval, in 72 MHz capture timer ticks.
+        pub(crate) ticks: u64,
+        /// Number of this pulse, counted across reboots.
+        pub(crate) count: u64,
         pub(crate) cpm: f32,
         pub(crate) val: f32,
+        /// Boost converter output at the last regulation step, in volts.
+        pub(crate) hv: f32,
     }
 
     pub(super) async fn run(
-        geiger_output_pin: Peri<'static, PB8>,
-        geiger_output_exti: Peri<'static, EXTI8>,
+        timer: &timer::SharedTimer,
+        boost_volts: &Cell<f32>,
         publisher: DynPublisher<'static, Message>,
         storage: &'static Storage,
+        mut settings: DynReceiver<'static, Settings>,
     ) {
-        let mut geiger_output = ExtiInput::new(geiger_output_pin, geiger_output_exti, Pull::None);
         let mut history = ConstGenericRingBuffer::<_, 100>::new();
-        let mut last = Instant::now();
+        let mut last = timer::Instant::ZERO;
         let mut count = storage
             .lock()
             .await
-            .read(b"count")
+            .read(storage::keys::COUNT)
             .await
             .unwrap_or(None)
             .unwrap_or(0u64);
         loop {
-            geiger_output.wait_for_falling_edge().await;
-            let now = Instant::now();
-            let dur = now.saturating_duration_since(last);
+            let now = timer.wait_for_pulse().await;
+            let Settings {
+                background_cpm,
+                sensitivity,
+                ..
+            } = settings.get().await;
+            if RESET.try_take().is_some() {
+                info!("count reset");
+                count = 0;
+            }
+            let ticks = now.ticks_since(last);
+            let dur = now.duration_since(last);
             last = now;
 
             // The history buffer is full, or the oldest record is passed 1 minus.
@@ -154,14 +342,18 @@ pub(crate) mod count {
                     let duration = latest.duration_since(*oldest);
                     let count = history.len();
                     cps = count as f32 / (duration.as_millis() as f32 / 1000.);
-                    value = (cps - GEIGER_BACKGROUND_LEVEL) / GEIGER_SENSITIVITY;
+                    value = (cps - background_cpm / 60.) / sensitivity;
                     // mR/h
                 }
             }
             let msg = Message {
+                time: now.duration_since(timer::Instant::ZERO).as_millis(),
                 dur: dur.as_millis(),
+                ticks,
+                count,
                 cpm: cps * 60.,
                 val: value * 8.76,
+                hv: boost_volts.get(),
             };
             info!(
                 "dur: {} ms, count: {}, cpm: {}, val: {} µSv/h = {} BED",
@@ -174,7 +366,12 @@ pub(crate) mod count {
             publisher.publish_immediate(msg);
 
             count += 1;
-            if let Err(e) = storage.lock().await.write(b"count", &count).await {
+            if let Err(e) = storage
+                .lock()
+                .await
+                .write(storage::keys::COUNT, &count)
+                .await
+            {
                 error!("Failed to store count: {:?}", e);
             }
         }
@@ -2,26 +2,33 @@
 #![no_main]
 
 use embassy_executor::Spawner;
+#[cfg(not(feature = "chaoskey"))]
+use embassy_stm32::usart::Uart;
 use embassy_stm32::{
     adc::Adc,
     bind_interrupts,
     peripherals::{ADC1, USART1, USART3, USB},
     time::Hertz,
-    usart::Uart,
     Config,
 };
 use embassy_sync::{
     blocking_mutex::raw::{NoopRawMutex, ThreadModeRawMutex},
     mutex::Mutex,
     pubsub::PubSubChannel,
+    watch::Watch,
 };
+use embassy_time::Timer;
 use sequential_storage::cache::NoCache;
 use static_cell::StaticCell;
 
 use {defmt_rtt as _, panic_probe as _};
 
 mod display;
+mod entropy;
 mod geiger;
+#[cfg(not(feature = "chaoskey"))]
+mod protocol;
+mod settings;
 mod storage;
 mod usb;
 
@@ -34,8 +41,13 @@ bind_interrupts!(
     }
 );
 
-static GEIGER_PUBLISHER: StaticCell<PubSubChannel<NoopRawMutex, geiger::count::Message, 5, 2, 1>> =
+static GEIGER_PUBLISHER: StaticCell<PubSubChannel<NoopRawMutex, geiger::count::Message, 5, 3, 1>> =
     StaticCell::new();
+static ENTROPY_PUBLISHER: StaticCell<PubSubChannel<NoopRawMutex, entropy::Message, 8, 2, 1>> =
+    StaticCell::new();
+static ENTROPY_STATUS: StaticCell<Watch<NoopRawMutex, entropy::Status, 3>> = StaticCell::new();
+static ENTROPY_OUTPUT: StaticCell<entropy::Output> = StaticCell::new();
+static SETTINGS: StaticCell<Watch<NoopRawMutex, settings::Settings, 3>> = StaticCell::new();
 static STORAGE: StaticCell<Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>> =
     StaticCell::new();
 
@@ -65,39 +77,61 @@ async fn main(spawner: Spawner) {
 
     let geiger_channel =
         GEIGER_PUBLISHER
-            .init(PubSubChannel::<NoopRawMutex, geiger::count::Message, 5, 2, 1>::new());
-
-    #[cfg(not(feature = "uart3_cdc"))]
-    let debug_uart = Uart::new(
-        p.USART1,
-        p.PA10,
-        p.PA9,
-        Irqs,
-        p.DMA1_CH4,
-        p.DMA1_CH5,
-        Default::default(),
-    )
-    .unwrap();
+            .init(PubSubChannel::<NoopRawMutex, geiger::count::Message, 5, 3, 1>::new());
+    let entropy_channel =
+        ENTROPY_PUBLISHER.init(PubSubChannel::<NoopRawMutex, entropy::Message, 8, 2, 1>::new());
+    let entropy_status = ENTROPY_STATUS.init(Watch::new());
+    let entropy_output = ENTROPY_OUTPUT.init(entropy::Output::new());
+    let settings = SETTINGS.init(Watch::new_with(settings::Settings::load(storage).await));
 
-    #[cfg(feature = "uart3_cdc")]
-    let debug_uart = Uart::new(
-        p.USART3,
-        p.PB11,
-        p.PB10,
-        Irqs,
-        p.DMA1_CH2,
-        p.DMA1_CH3,
-        Default::default(),
-    )
-    .unwrap();
+    #[cfg(not(feature = "chaoskey"))]
+    let serial = usb::Serial {
+        #[cfg(not(feature = "uart3_cdc"))]
+        uart: Uart::new(
+            p.USART1,
+            p.PA10,
+            p.PA9,
+            Irqs,
+            p.DMA1_CH4,
+            p.DMA1_CH5,
+            Default::default(),
+        )
+        .unwrap(),
+        #[cfg(feature = "uart3_cdc")]
+        uart: Uart::new(
+            p.USART3,
+            p.PB11,
+            p.PB10,
+            Irqs,
+            p.DMA1_CH2,
+            p.DMA1_CH3,
+            Default::default(),
+        )
+        .unwrap(),
+        modem: usb::ModemPins {
+            dtr: p.PB12,
+            rts: p.PB13,
+            cts: p.PB14,
+            dsr: p.PB15,
+            dcd: p.PA8,
+            ri: p.PB5,
+        },
+        geiger_subscriber: geiger_channel.dyn_subscriber().unwrap(),
+        entropy_status: entropy_status.dyn_receiver().unwrap(),
+        settings: settings.dyn_sender(),
+    };
+    #[cfg(feature = "chaoskey")]
+    let serial = usb::Serial;
 
     spawner.spawn(
         usb::run(
             p.USB,
             p.PA11,
             p.PA12,
-            debug_uart,
-            geiger_channel.dyn_subscriber().unwrap(),
+            serial,
+            entropy_status.dyn_receiver().unwrap(),
+            entropy_output,
+            storage,
         )
         .expect("Failed to spawn debug_uart task"),
     );
@@ -108,12 +142,25 @@ async fn main(spawner: Spawner) {
             p.PB9,
             p.TIM4,
             p.PB8,
-            p.EXTI8,
             geiger_channel.dyn_publisher().unwrap(),
             storage,
+            entropy_output,
+            settings.dyn_receiver().unwrap(),
+            settings.dyn_receiver().unwrap(),
         )
         .expect("Failed to spawn geiger driver task"),
     );
+    spawner.spawn(
+        entropy::run(
+            geiger_channel.dyn_subscriber().unwrap(),
+            entropy_channel.dyn_publisher().unwrap(),
+            entropy_status.dyn_sender(),
+            entropy_output,
+            storage,
+            settings.dyn_receiver().unwrap(),
+        )
+        .expect("Failed to spawn entropy task"),
+    );
     #[cfg(not(feature = "uart3_cdc"))]
     spawner.spawn(
         display::run(
@@ -125,7 +172,15 @@ async fn main(spawner: Spawner) {
             p.PA1,
             p.PA4,
             geiger_channel.dyn_subscriber().unwrap(),
+            entropy_status.dyn_receiver().unwrap(),
         )
         .expect("Failed to spawn display driver task"),
     );
+
+    // An updated firmware that does not get this far is not started again,
+    // the bootloader stays in DFU mode after a few tries.
+    Timer::after_secs(10).await;
+    if let Err(e) = storage.lock().await.confirm_boot() {
+        defmt::error!("Failed to confirm the firmware: {}", e);
+    }
 }
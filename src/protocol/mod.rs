@@ -0,0 +1,9 @@
+//! Instrument protocols served over the CLI serial port.
+//!
+//! Framing, parsing and reply encoding only, so nothing here depends on USB
+//! or the peripherals.
+
+pub(crate) mod scpi;
+
+pub(crate) use banana_gq as gq;
+pub(crate) use banana_telemetry::json;
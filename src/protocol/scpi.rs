@@ -0,0 +1,210 @@
+//! A small SCPI command set for lab automation.
+//!
+//! One command per line. Headers are case-insensitive and take either the
+//! short or the long form of each mnemonic, e.g. `MEAS:CPM?` or
+//! `measure:cpm?`. Errors go to a queue read with `SYSTem:ERRor?`.
+
+use core::fmt;
+
+/// Largest `RANDom:DATA?` request, in bytes.
+pub(crate) const MAX_RANDOM_BYTES: u32 = 1 << 20;
+
+/// SCPI for an unavailable value, e.g. a dose rate before enough pulses.
+const NOT_A_NUMBER: &str = "9.91E37";
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub(crate) enum Command<'a> {
+    /// `*IDN?`
+    Identify,
+    /// `*RST`, restores the default settings without storing them.
+    Reset,
+    /// `*CLS`, clears the error queue.
+    ClearStatus,
+    /// `MEASure:CPM?`
+    MeasureCpm,
+    /// `MEASure:DOSE?`, in µSv/h.
+    MeasureDose,
+    /// `MEASure:VOLTage?`, the measured boost output.
+    MeasureVoltage,
+    /// `SOURce:VOLTage?`, the boost setpoint.
+    SourceVoltage,
+    /// `SOURce:VOLTage <volts>`, the number is range checked by the
+    /// settings.
+    SetSourceVoltage(&'a str),
+    /// `SYSTem:ERRor[:NEXT]?`
+    SystemError,
+    /// `RANDom:DATA? <n>`, replied with a definite-length block.
+    RandomData(u32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum Error {
+    DataTypeError,
+    ParameterNotAllowed,
+    MissingParameter,
+    UndefinedHeader,
+    /// The command cannot run now, e.g. random data before the DRBG is
+    /// seeded or after a failed test stopped the output.
+    ExecutionError,
+    DataOutOfRange,
+    /// The line is longer than the CLI takes.
+    TooMuchData,
+    QueueOverflow,
+}
+
+impl Error {
+    fn code(self) -> i16 {
+        match self {
+            Error::DataTypeError => -104,
+            Error::ParameterNotAllowed => -108,
+            Error::MissingParameter => -109,
+            Error::UndefinedHeader => -113,
+            Error::ExecutionError => -200,
+            Error::DataOutOfRange => -222,
+            Error::TooMuchData => -223,
+            Error::QueueOverflow => -350,
+        }
+    }
+
+    fn message(self) -> &'static str {
+        match self {
+            Error::DataTypeError => "Data type error",
+            Error::ParameterNotAllowed => "Parameter not allowed",
+            Error::MissingParameter => "Missing parameter",
+            Error::UndefinedHeader => "Undefined header",
+            Error::ExecutionError => "Execution error",
+            Error::DataOutOfRange => "Data out of range",
+            Error::TooMuchData => "Too much data",
+            Error::QueueOverflow => "Queue overflow",
+        }
+    }
+}
+
+/// The error/event queue, oldest first.
+pub(crate) struct ErrorQueue(heapless::Deque<Error, 8>);
+
+impl ErrorQueue {
+    pub(crate) const fn new() -> Self {
+        Self(heapless::Deque::new())
+    }
+
+    /// Queues `error`, a full queue replaces its newest entry with
+    /// [`Error::QueueOverflow`].
+    pub(crate) fn push(&mut self, error: Error) {
+        if self.0.is_full() {
+            self.0.pop_back();
+            let _ = self.0.push_back(Error::QueueOverflow);
+        } else {
+            let _ = self.0.push_back(error);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Writes the reply to `SYSTem:ERRor?` for the oldest error.
+    pub(crate) fn write_next(&mut self, f: &mut impl fmt::Write) -> fmt::Result {
+        match self.0.pop_front() {
+            Some(error) => write!(f, "{},\"{}\"", error.code(), error.message()),
+            None => f.write_str("0,\"No error\""),
+        }
+    }
+}
+
+/// Parses one line. `Err(UndefinedHeader)` also means the line is not SCPI
+/// at all.
+pub(crate) fn parse(line: &str) -> Result<Command<'_>, Error> {
+    let line = line.trim();
+    let (header, parameter) = match line.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((header, parameter)) => (header, Some(parameter.trim())),
+        None => (line, None),
+    };
+    let (header, query) = match header.strip_suffix('?') {
+        Some(header) => (header, true),
+        None => (header, false),
+    };
+    let mut nodes = header.strip_prefix(':').unwrap_or(header).split(':');
+    let mut node = || nodes.next().unwrap_or("");
+    let (first, second, third) = (node(), node(), node());
+    if nodes.next().is_some() {
+        return Err(Error::UndefinedHeader);
+    }
+
+    let command = if let Some(common) = first.strip_prefix('*') {
+        if !second.is_empty() {
+            return Err(Error::UndefinedHeader);
+        }
+        match query {
+            true if common.eq_ignore_ascii_case("IDN") => Command::Identify,
+            false if common.eq_ignore_ascii_case("RST") => Command::Reset,
+            false if common.eq_ignore_ascii_case("CLS") => Command::ClearStatus,
+            _ => return Err(Error::UndefinedHeader),
+        }
+    } else if mnemonic(first, "MEASure") && third.is_empty() && query {
+        if mnemonic(second, "CPM") {
+            Command::MeasureCpm
+        } else if mnemonic(second, "DOSE") {
+            Command::MeasureDose
+        } else if mnemonic(second, "VOLTage") {
+            Command::MeasureVoltage
+        } else {
+            return Err(Error::UndefinedHeader);
+        }
+    } else if mnemonic(first, "SOURce") && mnemonic(second, "VOLTage") && third.is_empty() {
+        if query {
+            Command::SourceVoltage
+        } else {
+            let volts = parameter.ok_or(Error::MissingParameter)?;
+            volts.parse::<f32>().map_err(|_| Error::DataTypeError)?;
+            Command::SetSourceVoltage(volts)
+        }
+    } else if mnemonic(first, "SYSTem")
+        && mnemonic(second, "ERRor")
+        && (third.is_empty() || mnemonic(third, "NEXT"))
+        && query
+    {
+        Command::SystemError
+    } else if mnemonic(first, "RANDom") && mnemonic(second, "DATA") && third.is_empty() && query {
+        let n = parameter.ok_or(Error::MissingParameter)?;
+        let n: u32 = n.parse().map_err(|_| Error::DataTypeError)?;
+        if !(1..=MAX_RANDOM_BYTES).contains(&n) {
+            return Err(Error::DataOutOfRange);
+        }
+        Command::RandomData(n)
+    } else {
+        return Err(Error::UndefinedHeader);
+    };
+
+    let takes_parameter = matches!(
+        command,
+        Command::SetSourceVoltage(_) | Command::RandomData(_)
+    );
+    if parameter.is_some() && !takes_parameter {
+        return Err(Error::ParameterNotAllowed);
+    }
+    Ok(command)
+}
+
+/// Whether `node` is the short form (the upper case part) or the long form
+/// of `long`, ignoring case.
+fn mnemonic(node: &str, long: &str) -> bool {
+    let short = long.trim_end_matches(|c: char| c.is_ascii_lowercase());
+    node.eq_ignore_ascii_case(short) || node.eq_ignore_ascii_case(long)
+}
+
+/// Writes a numeric reply, [`NOT_A_NUMBER`] for NaN.
+pub(crate) fn write_number(f: &mut impl fmt::Write, value: f32) -> fmt::Result {
+    if value.is_nan() {
+        f.write_str(NOT_A_NUMBER)
+    } else {
+        write!(f, "{value}")
+    }
+}
+
+/// Writes the header of an IEEE 488.2 definite-length block of `len` bytes,
+/// `#` followed by the digit count and the length.
+pub(crate) fn write_block_header(f: &mut impl fmt::Write, len: u32) -> fmt::Result {
+    let digits = len.checked_ilog10().unwrap_or(0) + 1;
+    write!(f, "#{digits}{len}")
+}
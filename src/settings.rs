@@ -0,0 +1,162 @@
+//! Runtime settings, persisted in storage and changed from the CLI.
+//!
+//! The CLI owns the [`Watch`](embassy_sync::watch::Watch) sender, every task
+//! that depends on a setting holds a receiver and picks up changes as they
+//! are sent.
+
+use core::fmt;
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use sequential_storage::cache::NoCache;
+
+use crate::{entropy::debias, geiger, storage};
+
+type Storage = Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>;
+
+/// Debias method used when nothing else is configured at runtime.
+#[cfg(not(feature = "debias_von_neumann"))]
+const DEFAULT_DEBIAS: debias::Method = debias::Method::Peres;
+#[cfg(feature = "debias_von_neumann")]
+const DEFAULT_DEBIAS: debias::Method = debias::Method::VonNeumann;
+
+#[derive(Clone, Copy, PartialEq, defmt::Format)]
+pub(crate) struct Settings {
+    /// Boost converter setpoint, in volts.
+    pub(crate) boost_volts: f32,
+    /// Background count rate of the tube, in CPM.
+    pub(crate) background_cpm: f32,
+    /// Tube sensitivity, in CPS at 1 mR/h Co-60.
+    pub(crate) sensitivity: f32,
+    pub(crate) debias: debias::Method,
+}
+
+impl Settings {
+    pub(crate) const DEFAULT: Settings = Settings {
+        boost_volts: geiger::BOOST_VOLTS,
+        background_cpm: geiger::GEIGER_BACKGROUND_LEVEL * 60.,
+        sensitivity: geiger::GEIGER_SENSITIVITY,
+        debias: DEFAULT_DEBIAS,
+    };
+
+    /// Reads the stored settings, using the default for anything missing.
+    pub(crate) async fn load(storage: &Storage) -> Self {
+        let mut storage = storage.lock().await;
+        let mut settings = Self::DEFAULT;
+        for (key, value) in [
+            (storage::keys::BOOST_VOLTS, &mut settings.boost_volts),
+            (storage::keys::BACKGROUND_CPM, &mut settings.background_cpm),
+            (storage::keys::SENSITIVITY, &mut settings.sensitivity),
+        ] {
+            *value = storage.read_or_default(key, *value).await.unwrap_or(*value);
+        }
+        if let Ok(Some(method)) = storage.read::<_, u8>(storage::keys::DEBIAS).await {
+            settings.debias = match method {
+                0 => debias::Method::VonNeumann,
+                _ => debias::Method::Peres,
+            };
+        }
+        settings
+    }
+
+    pub(crate) fn get(&self, key: Key, f: &mut impl fmt::Write) -> fmt::Result {
+        match key {
+            Key::BoostVolts => write!(f, "{}", self.boost_volts),
+            Key::BackgroundCpm => write!(f, "{}", self.background_cpm),
+            Key::Sensitivity => write!(f, "{}", self.sensitivity),
+            Key::Debias => f.write_str(self.debias.name()),
+        }
+    }
+
+    /// Parses and range checks `value`, leaving the settings untouched if it
+    /// is invalid.
+    pub(crate) fn set(&mut self, key: Key, value: &str) -> Result<(), InvalidValue> {
+        let number = |range: core::ops::RangeInclusive<f32>| {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|value| range.contains(value))
+                .ok_or(InvalidValue(key))
+        };
+        match key {
+            Key::BoostVolts => self.boost_volts = number(300.0..=420.0)?,
+            Key::BackgroundCpm => self.background_cpm = number(0.0..=1000.0)?,
+            Key::Sensitivity => self.sensitivity = number(1.0..=10000.0)?,
+            Key::Debias => {
+                self.debias = debias::Method::from_name(value).ok_or(InvalidValue(key))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Persists the current value of `key`.
+    pub(crate) async fn store(&self, key: Key, storage: &Storage) -> Result<(), storage::Error> {
+        let mut storage = storage.lock().await;
+        match key {
+            Key::BoostVolts => {
+                storage
+                    .write(storage::keys::BOOST_VOLTS, &self.boost_volts)
+                    .await
+            }
+            Key::BackgroundCpm => {
+                storage
+                    .write(storage::keys::BACKGROUND_CPM, &self.background_cpm)
+                    .await
+            }
+            Key::Sensitivity => {
+                storage
+                    .write(storage::keys::SENSITIVITY, &self.sensitivity)
+                    .await
+            }
+            Key::Debias => {
+                let method: u8 = match self.debias {
+                    debias::Method::VonNeumann => 0,
+                    debias::Method::Peres => 1,
+                };
+                storage.write(storage::keys::DEBIAS, &method).await
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) enum Key {
+    BoostVolts,
+    BackgroundCpm,
+    Sensitivity,
+    Debias,
+}
+
+impl Key {
+    pub(crate) const ALL: [Key; 4] = [
+        Key::BoostVolts,
+        Key::BackgroundCpm,
+        Key::Sensitivity,
+        Key::Debias,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Key::BoostVolts => "hv",
+            Key::BackgroundCpm => "background",
+            Key::Sensitivity => "sensitivity",
+            Key::Debias => "debias",
+        }
+    }
+
+    /// Accepted values, for the CLI help.
+    pub(crate) fn help(self) -> &'static str {
+        match self {
+            Key::BoostVolts => "boost setpoint, 300 to 420 V",
+            Key::BackgroundCpm => "tube background, 0 to 1000 CPM",
+            Key::Sensitivity => "tube sensitivity, 1 to 10000 CPS per mR/h",
+            Key::Debias => "von-neumann or peres",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Key> {
+        Key::ALL.into_iter().find(|key| key.name() == name)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(crate) struct InvalidValue(pub(crate) Key);
@@ -9,6 +9,20 @@ const RANGE: core::ops::Range<u32> = 0x0001F000..0x00020000;
 
 pub type Error = sequential_storage::Error<embassy_stm32::flash::Error>;
 
+/// Keys of the stored items.
+///
+/// A key is matched against the leading bytes of each stored item, so all
+/// keys have the same length to keep one from matching the prefix of another.
+pub mod keys {
+    pub const COUNT: &[u8; 5] = b"count";
+    pub const HEALTH_FAILURES: &[u8; 5] = b"hfail";
+    pub const BOOST_VOLTS: &[u8; 5] = b"hvolt";
+    pub const BACKGROUND_CPM: &[u8; 5] = b"bgcpm";
+    pub const SENSITIVITY: &[u8; 5] = b"sensi";
+    pub const DEBIAS: &[u8; 5] = b"debia";
+    pub const LABEL: &[u8; 5] = b"label";
+}
+
 mod wrapper {
     use embassy_stm32::flash::{
         Blocking, Error, Flash, FLASH_SIZE, MAX_ERASE_SIZE, READ_SIZE, WRITE_SIZE,
@@ -94,7 +108,12 @@ impl<C, const N: usize> Storage<C, N> {
         .await
     }
 
-    #[allow(unused)]
+    /// Marks the running firmware as good, so the bootloader keeps starting
+    /// it after an update.
+    pub fn confirm_boot(&mut self) -> Result<(), embassy_stm32::flash::Error> {
+        banana_boot::confirm(&mut self.flash.0)
+    }
+
     pub async fn read_or_default<'a, 'b, K, V>(
         &'a mut self,
         key: &K,
@@ -0,0 +1,334 @@
+//! CDC-ACM function of the UART bridge.
+//!
+//! `embassy_usb::class::cdc_acm` has no SEND_BREAK and no access to its
+//! notification endpoint, which the bridge needs to report the modem input
+//! lines with SERIAL_STATE notifications. Otherwise this follows the same
+//! descriptors and requests.
+
+use core::{cell::Cell, ops::BitOr};
+
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_usb::{
+    class::cdc_acm::{ParityType, StopBits},
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut},
+    types::InterfaceNumber,
+    Builder, Handler,
+};
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0A;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+/// The line coding and control line requests, the SERIAL_STATE notification
+/// and SEND_BREAK.
+const ACM_CAPABILITIES: u8 = 0x02 | 0x04;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+const REQ_SEND_BREAK: u8 = 0x23;
+
+const NOTIFICATION_SERIAL_STATE: u8 = 0x20;
+/// Fits a whole SERIAL_STATE notification, 10 bytes, in one packet.
+const NOTIFICATION_MAX_PACKET_SIZE: u16 = 16;
+
+/// SEND_BREAK duration that holds the break until the next SEND_BREAK.
+pub(super) const BREAK_UNTIL_CLEARED: u16 = 0xFFFF;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(super) struct LineCoding {
+    pub(super) data_rate: u32,
+    pub(super) stop_bits: StopBits,
+    pub(super) parity_type: ParityType,
+    pub(super) data_bits: u8,
+}
+
+impl Default for LineCoding {
+    fn default() -> Self {
+        Self {
+            data_rate: 115_200,
+            stop_bits: StopBits::One,
+            parity_type: ParityType::None,
+            data_bits: 8,
+        }
+    }
+}
+
+/// The bitmap of a SERIAL_STATE notification.
+#[derive(Clone, Copy, PartialEq, Eq, Default, defmt::Format)]
+pub(super) struct SerialState(u16);
+
+impl SerialState {
+    /// DCD, `bRxCarrier` in the specification.
+    pub(super) const DCD: Self = Self(1 << 0);
+    /// DSR, `bTxCarrier` in the specification.
+    pub(super) const DSR: Self = Self(1 << 1);
+    pub(super) const BREAK: Self = Self(1 << 2);
+    pub(super) const RING: Self = Self(1 << 3);
+    pub(super) const FRAMING: Self = Self(1 << 4);
+    pub(super) const PARITY: Self = Self(1 << 5);
+    pub(super) const OVERRUN: Self = Self(1 << 6);
+
+    pub(super) fn set(&mut self, flag: Self, value: bool) {
+        if value {
+            self.0 |= flag.0;
+        } else {
+            self.0 &= !flag.0;
+        }
+    }
+}
+
+impl BitOr for SerialState {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// Written by the control handler, read by the bridge.
+pub(super) struct Shared {
+    line_coding: Cell<LineCoding>,
+    dtr: Cell<bool>,
+    rts: Cell<bool>,
+    changed: Signal<ThreadModeRawMutex, ()>,
+    send_break: Signal<ThreadModeRawMutex, u16>,
+}
+
+impl Shared {
+    pub(super) fn line_coding(&self) -> LineCoding {
+        self.line_coding.get()
+    }
+
+    pub(super) fn dtr(&self) -> bool {
+        self.dtr.get()
+    }
+
+    pub(super) fn rts(&self) -> bool {
+        self.rts.get()
+    }
+
+    /// Waits for the host to change the line coding or the control lines.
+    pub(super) async fn changed(&self) {
+        self.changed.wait().await
+    }
+
+    /// Waits for a SEND_BREAK request and returns its duration in ms, zero
+    /// ends the break.
+    pub(super) async fn send_break(&self) -> u16 {
+        self.send_break.wait().await
+    }
+}
+
+pub(super) struct State<'a> {
+    control: Option<Control<'a>>,
+    shared: Shared,
+    supported: fn(&LineCoding) -> bool,
+}
+
+impl State<'_> {
+    /// SET_LINE_CODING requests for a line coding that is not `supported`
+    /// are rejected, the previous one stays in place.
+    pub(super) fn new(supported: fn(&LineCoding) -> bool) -> Self {
+        Self {
+            control: None,
+            supported,
+            shared: Shared {
+                line_coding: Cell::new(LineCoding::default()),
+                dtr: Cell::new(false),
+                rts: Cell::new(false),
+                changed: Signal::new(),
+                send_break: Signal::new(),
+            },
+        }
+    }
+}
+
+struct Control<'a> {
+    comm_if: InterfaceNumber,
+    shared: &'a Shared,
+    supported: fn(&LineCoding) -> bool,
+}
+
+impl Handler for Control<'_> {
+    fn reset(&mut self) {
+        self.shared.line_coding.set(LineCoding::default());
+        self.shared.dtr.set(false);
+        self.shared.rts.set(false);
+        self.shared.changed.signal(());
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return None;
+        }
+        match req.request {
+            // Not supported, but hosts send it anyway.
+            REQ_SEND_ENCAPSULATED_COMMAND => {}
+            REQ_SET_LINE_CODING if data.len() >= 7 => {
+                let line_coding = LineCoding {
+                    data_rate: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+                    stop_bits: data[4].into(),
+                    parity_type: data[5].into(),
+                    data_bits: data[6],
+                };
+                if !(self.supported)(&line_coding) {
+                    return Some(OutResponse::Rejected);
+                }
+                self.shared.line_coding.set(line_coding);
+                self.shared.changed.signal(());
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.shared.dtr.set(req.value & 0x0001 != 0);
+                self.shared.rts.set(req.value & 0x0002 != 0);
+                self.shared.changed.signal(());
+            }
+            REQ_SEND_BREAK => self.shared.send_break.signal(req.value),
+            _ => return Some(OutResponse::Rejected),
+        }
+        Some(OutResponse::Accepted)
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Class
+            || req.recipient != Recipient::Interface
+            || req.index != u8::from(self.comm_if) as u16
+        {
+            return None;
+        }
+        match req.request {
+            REQ_GET_LINE_CODING if req.length >= 7 && buf.len() >= 7 => {
+                let line_coding = self.shared.line_coding.get();
+                buf[0..4].copy_from_slice(&line_coding.data_rate.to_le_bytes());
+                buf[4] = line_coding.stop_bits as u8;
+                buf[5] = line_coding.parity_type as u8;
+                buf[6] = line_coding.data_bits;
+                Some(InResponse::Accepted(&buf[..7]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+pub(super) struct AcmClass<'d, D: Driver<'d>> {
+    comm_if: InterfaceNumber,
+    notify_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+    shared: &'d Shared,
+}
+
+impl<'d, D: Driver<'d>> AcmClass<'d, D> {
+    pub(super) fn new(
+        builder: &mut Builder<'d, D>,
+        state: &'d mut State<'d>,
+        max_packet_size: u16,
+    ) -> Self {
+        let mut function = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
+
+        let mut interface = function.interface();
+        let comm_if = interface.interface_number();
+        let data_if = u8::from(comm_if) + 1;
+        let mut alt =
+            interface.alt_setting(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE, None);
+        // CDC 1.10
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01]);
+        // No call management, the data interface carries no commands.
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, data_if]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, ACM_CAPABILITIES]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if.into(), data_if]);
+        let notify_ep = alt.endpoint_interrupt_in(None, NOTIFICATION_MAX_PACKET_SIZE, 255);
+
+        let mut interface = function.interface();
+        let mut alt = interface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, None);
+        let read_ep = alt.endpoint_bulk_out(None, max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(None, max_packet_size);
+        drop(function);
+
+        let control = state.control.insert(Control {
+            comm_if,
+            shared: &state.shared,
+            supported: state.supported,
+        });
+        builder.handler(control);
+
+        Self {
+            comm_if,
+            notify_ep,
+            read_ep,
+            write_ep,
+            shared: &state.shared,
+        }
+    }
+
+    pub(super) fn split(self) -> (Sender<'d, D>, Receiver<'d, D>, Notifier<'d, D>, &'d Shared) {
+        (
+            Sender(self.write_ep),
+            Receiver(self.read_ep),
+            Notifier {
+                comm_if: self.comm_if,
+                ep: self.notify_ep,
+            },
+            self.shared,
+        )
+    }
+}
+
+pub(super) struct Sender<'d, D: Driver<'d>>(D::EndpointIn);
+
+impl<'d, D: Driver<'d>> Sender<'d, D> {
+    /// Waits for the host to configure the device.
+    pub(super) async fn wait_connection(&mut self) {
+        self.0.wait_enabled().await
+    }
+
+    pub(super) async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.0.write(data).await
+    }
+}
+
+pub(super) struct Receiver<'d, D: Driver<'d>>(D::EndpointOut);
+
+impl<'d, D: Driver<'d>> Receiver<'d, D> {
+    pub(super) async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.0.read(data).await
+    }
+}
+
+pub(super) struct Notifier<'d, D: Driver<'d>> {
+    comm_if: InterfaceNumber,
+    ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> Notifier<'d, D> {
+    /// Sends a SERIAL_STATE notification. It waits for the host to poll the
+    /// endpoint, which it only does while the port is open.
+    pub(super) async fn serial_state(&mut self, state: SerialState) -> Result<(), EndpointError> {
+        let [state_lo, state_hi] = state.0.to_le_bytes();
+        let notification = [
+            0xA1,
+            NOTIFICATION_SERIAL_STATE,
+            0,
+            0,
+            self.comm_if.into(),
+            0,
+            2,
+            0,
+            state_lo,
+            state_hi,
+        ];
+        self.ep.write(&notification).await
+    }
+}
@@ -1,50 +1,770 @@
+mod command;
+mod edit;
+
+use core::fmt::Write;
+
+use banana_telemetry::Telemetry;
 use defmt::*;
-use embassy_futures::select::{select, Either};
+use embassy_futures::select::{select4, Either4};
 use embassy_stm32::usb::{Driver, Instance};
-use embassy_sync::pubsub::DynSubscriber;
-use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_sync::{
+    blocking_mutex::raw::ThreadModeRawMutex,
+    mutex::Mutex,
+    pubsub::DynSubscriber,
+    watch::{DynReceiver, DynSender},
+};
+use embassy_time::{Duration, Ticker, Timer};
+use embassy_usb::{
+    class::cdc_acm::{CdcAcmClass, Receiver},
+    driver::EndpointError,
+};
+use embedded_io_async::Write as _;
+use sequential_storage::cache::NoCache;
+
+use crate::{
+    entropy, geiger,
+    protocol::{gq, json, scpi},
+    settings::{self, Settings},
+    storage,
+};
+
+use self::command::Command;
+use super::{uart, writer::PacketWriter};
 
-use crate::geiger;
+type Storage = Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+enum Mode {
+    /// Human-readable dose and entropy status lines, with an interactive
+    /// command line.
+    Telemetry,
+    /// The telemetry as binary frames for host tools, see the
+    /// `banana-telemetry` crate.
+    Frames,
+    /// The telemetry as JSON Lines, numbers that are not available yet are
+    /// `null`.
+    Json,
+    /// Unconditioned noise source samples for offline entropy assessment.
+    ///
+    /// Each sample is a 16-byte record of two little-endian `u64`: the pulse
+    /// number, counted across reboots, then the inter-arrival interval in
+    /// 72 MHz capture timer ticks. A gap in the pulse numbers means samples
+    /// were dropped because the host did not read fast enough.
+    Raw,
+    /// A continuous binary stream of DRBG output, reseeded from conditioned
+    /// noise. USB flow control paces the stream to the host.
+    Random,
+    /// A binary stream of full-entropy conditioned bytes, about one block of
+    /// 32 bytes per hour at background level.
+    TrueRandom,
+    /// GQ-RFC1201 for GQ GMC logging software, entered as soon as such a
+    /// command arrives. Nothing is sent unless requested.
+    Gq,
+    /// SCPI for lab automation, entered as soon as an SCPI command arrives.
+    /// Like [`Mode::Gq`] there is no echo and nothing unrequested.
+    Scpi,
+}
+
+impl Mode {
+    fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "telemetry" => Some(Mode::Telemetry),
+            "frames" => Some(Mode::Frames),
+            "json" => Some(Mode::Json),
+            "raw" => Some(Mode::Raw),
+            "random" => Some(Mode::Random),
+            "true-random" => Some(Mode::TrueRandom),
+            "gq" => Some(Mode::Gq),
+            "scpi" => Some(Mode::Scpi),
+            _ => None,
+        }
+    }
+}
 
 pub(super) async fn transfer<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    class: CdcAcmClass<'d, Driver<'d, T>>,
     mut geiger_subscriber: DynSubscriber<'static, geiger::count::Message>,
+    mut entropy_status: DynReceiver<'static, entropy::Status>,
+    entropy_output: &'static entropy::Output,
+    config: DynSender<'static, Settings>,
+    storage: &'static Storage,
 ) {
+    let (sender, mut receiver) = class.split();
+    let mut writer = PacketWriter::new(sender);
     loop {
-        class.wait_connection().await;
+        writer.wait_connection().await;
         info!("Connected");
-        let _ = interacts(class, &mut geiger_subscriber).await;
+        let _ = interacts(
+            &mut receiver,
+            &mut writer,
+            &mut geiger_subscriber,
+            &mut entropy_status,
+            entropy_output,
+            &config,
+            storage,
+        )
+        .await;
         info!("Disconnected");
     }
 }
 
 pub(super) async fn interacts<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    receiver: &mut Receiver<'d, Driver<'d, T>>,
+    writer: &mut PacketWriter<'d, T>,
     geiger_subscriber: &mut DynSubscriber<'static, geiger::count::Message>,
-) {
-    use core::fmt::Write;
+    entropy_status: &mut DynReceiver<'static, entropy::Status>,
+    entropy_output: &'static entropy::Output,
+    config: &DynSender<'static, Settings>,
+    storage: &'static Storage,
+) -> Result<(), EndpointError> {
     let mut line_buffer = [0u8; 128];
-    let mut line = heapless::Vec::<u8, 64>::new();
+    let mut random = [0u8; 64];
+    let mut line = heapless::Vec::<u8, 128>::new();
+    let mut editor = edit::LineEditor::new();
+    let mut gq_parser = gq::Parser::new();
+    let mut heartbeat = false;
+    let mut heartbeat_pulses = 0u32;
+    let mut heartbeat_ticker = Ticker::every(Duration::from_secs(1));
+    let mut latest = None;
+    let mut scpi_errors = scpi::ErrorQueue::new();
+    let mut seq = 0u32;
+    let mut mode = Mode::Telemetry;
     loop {
-        match select(
-            class.read_packet(&mut line_buffer),
-            geiger_subscriber.next_message_pure(),
+        // In the random modes telemetry is not read at all, so a geiger pulse
+        // never interrupts the stream.
+        let sample = async {
+            match mode {
+                Mode::Telemetry | Mode::Frames | Mode::Json | Mode::Raw | Mode::Gq | Mode::Scpi => {
+                    geiger_subscriber.next_message_pure().await
+                }
+                Mode::Random | Mode::TrueRandom => core::future::pending().await,
+            }
+        };
+        // Dropped whenever another branch wins, both reads are cancel safe.
+        let fill_random = async {
+            match mode {
+                // A stopped output ends the stream, commands still work.
+                Mode::Random => match entropy_output.read_drbg(&mut random, false).await {
+                    Ok(()) => random.len(),
+                    Err(_) => core::future::pending().await,
+                },
+                Mode::TrueRandom => match entropy_output.read_true().await {
+                    Ok(block) => {
+                        random[..block.len()].copy_from_slice(&block);
+                        block.len()
+                    }
+                    Err(_) => core::future::pending().await,
+                },
+                Mode::Telemetry | Mode::Frames | Mode::Json | Mode::Raw | Mode::Gq | Mode::Scpi => {
+                    core::future::pending().await
+                }
+            }
+        };
+        let beat = async {
+            match mode {
+                Mode::Gq if heartbeat => heartbeat_ticker.next().await,
+                _ => core::future::pending().await,
+            }
+        };
+        match select4(
+            receiver.read_packet(&mut line_buffer),
+            sample,
+            fill_random,
+            beat,
         )
         .await
         {
-            Either::First(_result) => {
-                // if let Ok(n) = result {
-                //     info!("Read {} bytes {:a}", n, line_buffer[..n]);
-                // }
+            Either4::First(result) => {
+                let n = result?;
+                let mut echo = heapless::Vec::<u8, 64>::new();
+                for &byte in &line_buffer[..n] {
+                    let echoed = echo.len();
+                    let command = editor.push(byte, &mut echo);
+                    let gq_command = match gq_parser.push(byte) {
+                        Some(Ok(command)) => Some(command),
+                        Some(Err(unsupported)) => {
+                            warn!("Unsupported GQ command {=[u8]:a}", unsupported.name());
+                            None
+                        }
+                        None => None,
+                    };
+                    if gq_parser.receiving() || gq_command.is_some() {
+                        // GQ-RFC1201 frames are binary, never echo them.
+                        echo.truncate(echoed);
+                    }
+                    if let Some(gq_command) = gq_command {
+                        editor.clear();
+                        if mode != Mode::Gq {
+                            info!("Mode: {}", Mode::Gq);
+                            mode = Mode::Gq;
+                        }
+                        match gq_command {
+                            gq::Command::Heartbeat(on) => {
+                                heartbeat = on;
+                                heartbeat_pulses = 0;
+                                heartbeat_ticker.reset();
+                            }
+                            other => respond_gq(writer, other, latest.as_ref()).await?,
+                        }
+                        continue;
+                    }
+                    let input = match command {
+                        Some(Ok(input)) => input,
+                        Some(Err(edit::TooLong)) => {
+                            warn!("Command line too long");
+                            match mode {
+                                Mode::Telemetry => {
+                                    writer.write_all(&echo).await?;
+                                    echo.clear();
+                                    writer.write_all(edit::TooLong::MESSAGE).await?;
+                                }
+                                Mode::Scpi => scpi_errors.push(scpi::Error::TooMuchData),
+                                _ => {}
+                            }
+                            continue;
+                        }
+                        None => {
+                            if echo.len() > 56 {
+                                if mode == Mode::Telemetry {
+                                    writer.write_all(&echo).await?;
+                                }
+                                echo.clear();
+                            }
+                            continue;
+                        }
+                    };
+                    let input = core::str::from_utf8(&input).unwrap_or_default();
+                    let parsed = command::parse(input);
+                    // Lines that are not CLI commands may be SCPI. Only a valid
+                    // SCPI command switches to SCPI mode, once there anything
+                    // unknown is an SCPI error.
+                    let scpi_command = match parsed {
+                        Err(command::Error::Unknown) if !input.is_empty() => {
+                            match scpi::parse(input) {
+                                Ok(scpi_command) => Some(Ok(scpi_command)),
+                                Err(error) if mode == Mode::Scpi => Some(Err(error)),
+                                Err(_) => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    // Echo is only wanted on a terminal, not in the binary
+                    // modes or for an instrument controller.
+                    if mode == Mode::Telemetry && scpi_command.is_none() {
+                        writer.write_all(&echo).await?;
+                    }
+                    echo.clear();
+                    if let Some(scpi_command) = scpi_command {
+                        if mode != Mode::Scpi {
+                            info!("Mode: {}", Mode::Scpi);
+                            mode = Mode::Scpi;
+                        }
+                        match scpi_command {
+                            Ok(scpi_command) => {
+                                respond_scpi(
+                                    writer,
+                                    scpi_command,
+                                    &mut scpi_errors,
+                                    latest.as_ref(),
+                                    entropy_output,
+                                    config,
+                                    storage,
+                                )
+                                .await?
+                            }
+                            Err(error) => {
+                                warn!("SCPI {=str}: {}", input, error);
+                                scpi_errors.push(error);
+                            }
+                        }
+                        continue;
+                    }
+                    if input.is_empty() {
+                        continue;
+                    }
+                    match parsed {
+                        Ok(command) => {
+                            execute(
+                                writer,
+                                command,
+                                &mut mode,
+                                latest.as_ref(),
+                                entropy_status,
+                                entropy_output,
+                                config,
+                                storage,
+                            )
+                            .await?
+                        }
+                        Err(error) => {
+                            warn!("Command {=str}: {}", input, error);
+                            if mode == Mode::Telemetry {
+                                writer.write_all(error.message().as_bytes()).await?;
+                                writer.write_all(b"\r\n").await?;
+                            }
+                        }
+                    }
+                }
+                if mode == Mode::Telemetry {
+                    writer.write_all(&echo).await?;
+                }
+            }
+            Either4::Second(message) if mode == Mode::Gq => {
+                heartbeat_pulses += 1;
+                latest = Some(message);
+            }
+            Either4::Second(message) if mode == Mode::Scpi => latest = Some(message),
+            Either4::Second(message) if mode == Mode::Frames => {
+                let telemetry = telemetry(seq, &message);
+                seq = seq.wrapping_add(1);
+                latest = Some(message);
+                let mut frame = [0u8; banana_telemetry::MAX_FRAME_LEN];
+                match telemetry.encode(&mut frame) {
+                    Ok(frame) => writer.write_all(frame).await?,
+                    Err(e) => error!("Telemetry frame: {}", Debug2Format(&e)),
+                }
+            }
+            Either4::Second(message) if mode == Mode::Json => {
+                let mut record = heapless::Vec::<u8, { json::MAX_LINE_LEN }>::new();
+                let written = json::write_line(&mut record, &telemetry(seq, &message));
+                seq = seq.wrapping_add(1);
+                latest = Some(message);
+                if written.is_ok() {
+                    writer.write_all(&record).await?;
+                }
             }
-            Either::Second(geiger::count::Message { dur, cpm, val }) => {
+            Either4::Second(message) if mode == Mode::Raw => {
+                let mut record = [0u8; 16];
+                record[..8].copy_from_slice(&message.count.to_le_bytes());
+                record[8..].copy_from_slice(&message.ticks.to_le_bytes());
+                latest = Some(message);
+                writer.write_all(&record).await?;
+            }
+            Either4::Second(message) => {
+                let geiger::count::Message { dur, cpm, val, .. } = message;
+                latest = Some(message);
+                // Keep a half typed command line below the telemetry.
+                let typing = !editor.pending().is_empty();
+                if typing {
+                    writer.write_all(b"\r\x1b[K").await?;
+                }
                 if core::write!(&mut line, "Dur:{dur} ms CPM:{cpm} RD:{val:.5} uSv/h\n").is_ok() {
-                    if let Ok(()) = class.write_packet(&line).await {
-                        info!("Write {} bytes", line.len());
+                    writer.write_all(&line).await?;
+                }
+                line.clear();
+                if let Some(entropy::Status {
+                    state,
+                    debias,
+                    estimate,
+                    health_failures,
+                    ..
+                }) = entropy_status.try_get()
+                {
+                    if core::write!(
+                        &mut line,
+                        "Debias:{} In:{} Out:{} Eff:{:.2}\n",
+                        debias.method.name(),
+                        debias.bits_in,
+                        debias.bits_out,
+                        debias.efficiency()
+                    )
+                    .is_ok()
+                    {
+                        writer.write_all(&line).await?;
                     }
                     line.clear();
+                    if let Some(estimate) = estimate {
+                        if core::write!(
+                            &mut line,
+                            "Hmin:{:.2} MCV:{:.2} Collision:{:.2} Markov:{:.2}\n",
+                            estimate.min(),
+                            estimate.most_common_value,
+                            estimate.collision,
+                            estimate.markov
+                        )
+                        .is_ok()
+                        {
+                            writer.write_all(&line).await?;
+                        }
+                        line.clear();
+                    }
+                    if state != entropy::State::Running {
+                        if core::write!(
+                            &mut line,
+                            "State:{state} HealthFailures:{health_failures}\n"
+                        )
+                        .is_ok()
+                        {
+                            writer.write_all(&line).await?;
+                        }
+                        line.clear();
+                    }
+                }
+                if typing {
+                    writer.write_all(editor.pending()).await?;
+                }
+            }
+            Either4::Third(n) => {
+                // Blocks until the host reads, which is the flow control. The
+                // DRBG stream is all full packets, so there is nothing to
+                // flush, a true entropy block goes out as soon as it arrives.
+                writer.write_all(&random[..n]).await?;
+                if mode == Mode::Random {
+                    continue;
+                }
+            }
+            Either4::Fourth(()) => {
+                let pulses = core::mem::take(&mut heartbeat_pulses);
+                writer.write_all(&gq::cps(pulses)).await?;
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn execute<'d, T: Instance + 'd>(
+    writer: &mut PacketWriter<'d, T>,
+    command: Command<'_>,
+    mode: &mut Mode,
+    latest: Option<&geiger::count::Message>,
+    entropy_status: &mut DynReceiver<'static, entropy::Status>,
+    entropy_output: &'static entropy::Output,
+    config: &DynSender<'static, Settings>,
+    storage: &'static Storage,
+) -> Result<(), EndpointError> {
+    let mut response = heapless::Vec::<u8, 128>::new();
+    match command {
+        Command::Help => writer.write_all(command::HELP.as_bytes()).await?,
+        Command::Status => {
+            if let Some(geiger::count::Message {
+                dur,
+                count,
+                cpm,
+                val,
+                ..
+            }) = latest
+            {
+                let _ = core::write!(
+                    &mut response,
+                    "Pulses:{count} Dur:{dur} ms CPM:{cpm} RD:{val:.5} uSv/h\r\n"
+                );
+                writer.write_all(&response).await?;
+                response.clear();
+            }
+            if let Some(entropy::Status {
+                state,
+                debias,
+                condition,
+                drbg,
+                estimate,
+                health_failures,
+            }) = entropy_status.try_get()
+            {
+                let _ = core::write!(
+                    &mut response,
+                    "State:{state} HealthFailures:{health_failures}\r\n"
+                );
+                writer.write_all(&response).await?;
+                response.clear();
+                let _ = core::write!(
+                    &mut response,
+                    "Debias:{} In:{} Out:{} Eff:{:.2}\r\n",
+                    debias.method.name(),
+                    debias.bits_in,
+                    debias.bits_out,
+                    debias.efficiency()
+                );
+                writer.write_all(&response).await?;
+                response.clear();
+                let _ = core::write!(
+                    &mut response,
+                    "Blocks:{} Credit:{:.0}b DRBG:{} Reseeds:{} Counter:{}\r\n",
+                    condition.blocks,
+                    condition.credit,
+                    if drbg.seeded { "seeded" } else { "unseeded" },
+                    drbg.reseeds,
+                    drbg.reseed_counter
+                );
+                writer.write_all(&response).await?;
+                response.clear();
+                if let Some(estimate) = estimate {
+                    let _ = core::write!(
+                        &mut response,
+                        "Hmin:{:.2} MCV:{:.2} Collision:{:.2} Markov:{:.2}\r\n",
+                        estimate.min(),
+                        estimate.most_common_value,
+                        estimate.collision,
+                        estimate.markov
+                    );
+                    writer.write_all(&response).await?;
+                    response.clear();
+                }
+            }
+            let uart::Errors {
+                framing,
+                parity,
+                overrun,
+                noise,
+                overflow,
+                line_codings,
+            } = uart::errors();
+            let _ = core::write!(
+                &mut response,
+                "UART Framing:{framing} Parity:{parity} Overrun:{overrun} Noise:{noise}\r\n"
+            );
+            writer.write_all(&response).await?;
+            response.clear();
+            let _ = core::write!(
+                &mut response,
+                "UART Overflow:{overflow} LineCodings:{line_codings}\r\n"
+            );
+            writer.write_all(&response).await?;
+        }
+        Command::ConfigGet(key) => {
+            let current = config.try_get().unwrap_or(Settings::DEFAULT);
+            let keys = match &key {
+                Some(key) => core::slice::from_ref(key),
+                None => &settings::Key::ALL[..],
+            };
+            for &key in keys {
+                let _ = core::write!(&mut response, "{} = ", key.name());
+                let _ = current.get(key, &mut response);
+                let _ = core::write!(&mut response, " ({})\r\n", key.help());
+                writer.write_all(&response).await?;
+                response.clear();
+            }
+        }
+        Command::ConfigSet(key, value) => {
+            let mut new = config.try_get().unwrap_or(Settings::DEFAULT);
+            match new.set(key, value) {
+                Err(settings::InvalidValue(key)) => {
+                    let _ = core::write!(&mut response, "invalid value, {}\r\n", key.help());
+                }
+                Ok(()) => {
+                    info!("Setting {}: {}", key, new);
+                    config.send(new);
+                    if let Err(e) = new.store(key, storage).await {
+                        error!("Failed to store setting: {:?}", e);
+                        let _ = core::write!(&mut response, "applied but not stored\r\n");
+                    }
+                }
+            }
+            writer.write_all(&response).await?;
+        }
+        // The DRBG is seeded from the first conditioned blocks, which can
+        // take hours after boot, and a failed test stops it until reboot.
+        // Waiting for it would leave the command line unresponsive.
+        Command::Random(n) => {
+            if let Err(stopped) = entropy_output.stopped() {
+                let _ = core::write!(&mut response, "{stopped}\r\n");
+                writer.write_all(&response).await?;
+                return Ok(());
+            }
+            if !entropy_output.stats().await.seeded {
+                return writer
+                    .write_all(b"not ready, the DRBG is not seeded yet\r\n")
+                    .await;
+            }
+            let mut bytes = [0u8; 32];
+            let mut remaining = n;
+            while remaining > 0 {
+                let chunk = &mut bytes[..remaining.min(32)];
+                if let Err(stopped) = entropy_output.read_drbg(chunk, false).await {
+                    let _ = core::write!(&mut response, "{stopped}\r\n");
+                    writer.write_all(&response).await?;
+                    break;
+                }
+                for byte in chunk.iter() {
+                    let _ = core::write!(&mut response, "{byte:02x}");
+                }
+                let _ = core::write!(&mut response, "\r\n");
+                writer.write_all(&response).await?;
+                response.clear();
+                remaining -= chunk.len();
+            }
+        }
+        Command::ResetCount => {
+            geiger::count::reset();
+            if let Err(e) = storage
+                .lock()
+                .await
+                .write(storage::keys::COUNT, &0u64)
+                .await
+            {
+                error!("Failed to store count: {:?}", e);
+            }
+            writer.write_all(b"pulse count reset\r\n").await?;
+        }
+        Command::LabelGet => {
+            let _ = core::write!(
+                &mut response,
+                "serial = {}\r\nlabel = {}\r\n",
+                super::serial_number(),
+                super::read_label(storage).await
+            );
+            writer.write_all(&response).await?;
+        }
+        Command::LabelSet(label) => store_label(writer, storage, label).await?,
+        Command::LabelClear => store_label(writer, storage, "").await?,
+        Command::Reboot => {
+            writer.write_all(b"rebooting\r\n").await?;
+            writer.flush().await?;
+            // Give the host a moment to fetch the reply.
+            Timer::after_millis(50).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        Command::Mode(new_mode) => {
+            info!("Mode: {}", new_mode);
+            *mode = new_mode;
+        }
+    }
+    Ok(())
+}
+
+async fn store_label<'d, T: Instance + 'd>(
+    writer: &mut PacketWriter<'d, T>,
+    storage: &'static Storage,
+    label: &str,
+) -> Result<(), EndpointError> {
+    let result = storage
+        .lock()
+        .await
+        .write(storage::keys::LABEL, &label.as_bytes())
+        .await;
+    match result {
+        Ok(()) => {
+            writer
+                .write_all(b"label stored, the USB product name changes after a reboot\r\n")
+                .await
+        }
+        Err(e) => {
+            error!("Failed to store label: {:?}", e);
+            writer.write_all(b"label not stored\r\n").await
+        }
+    }
+}
+
+/// The fields of `message` sent in `frames` and `json` modes.
+fn telemetry(seq: u32, message: &geiger::count::Message) -> Telemetry {
+    Telemetry {
+        seq,
+        t_ms: message.time,
+        dur_ms: message.dur,
+        ticks: message.ticks,
+        count: message.count,
+        cpm: message.cpm,
+        usvh: message.val,
+        hv: message.hv,
+    }
+}
+
+async fn respond_gq<'d, T: Instance + 'd>(
+    writer: &mut PacketWriter<'d, T>,
+    command: gq::Command,
+    latest: Option<&geiger::count::Message>,
+) -> Result<(), EndpointError> {
+    let cpm = latest.map_or(0., |message| message.cpm);
+    match command {
+        gq::Command::GetVersion => writer.write_all(gq::VERSION).await,
+        gq::Command::GetCpm => writer.write_all(&gq::cpm(cpm)).await,
+        gq::Command::GetCps => writer.write_all(&gq::cps((cpm / 60.) as u32)).await,
+        gq::Command::GetSerial => {
+            writer
+                .write_all(&gq::serial(embassy_stm32::uid::uid()))
+                .await
+        }
+        // Bus powered, there is no battery to report.
+        gq::Command::GetVolts => writer.write_all(&gq::volts(5.)).await,
+        gq::Command::Reboot => cortex_m::peripheral::SCB::sys_reset(),
+        gq::Command::Heartbeat(_) | gq::Command::PowerOn | gq::Command::PowerOff => Ok(()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn respond_scpi<'d, T: Instance + 'd>(
+    writer: &mut PacketWriter<'d, T>,
+    command: scpi::Command<'_>,
+    errors: &mut scpi::ErrorQueue,
+    latest: Option<&geiger::count::Message>,
+    entropy_output: &'static entropy::Output,
+    config: &DynSender<'static, Settings>,
+    storage: &'static Storage,
+) -> Result<(), EndpointError> {
+    let mut response = heapless::Vec::<u8, 64>::new();
+    let measured = |value: fn(&geiger::count::Message) -> f32| latest.map_or(f32::NAN, value);
+    let current = config.try_get().unwrap_or(Settings::DEFAULT);
+    match command {
+        scpi::Command::Identify => {
+            let _ = core::write!(
+                &mut response,
+                "Tnze,Banana RNG,{},{}",
+                super::serial_number(),
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        scpi::Command::Reset => {
+            info!("Setting defaults");
+            config.send(Settings::DEFAULT);
+            return Ok(());
+        }
+        scpi::Command::ClearStatus => {
+            errors.clear();
+            return Ok(());
+        }
+        scpi::Command::MeasureCpm => {
+            let _ = scpi::write_number(&mut response, measured(|message| message.cpm));
+        }
+        scpi::Command::MeasureDose => {
+            let _ = scpi::write_number(&mut response, measured(|message| message.val));
+        }
+        scpi::Command::MeasureVoltage => {
+            let _ = scpi::write_number(&mut response, measured(|message| message.hv));
+        }
+        scpi::Command::SourceVoltage => {
+            let _ = scpi::write_number(&mut response, current.boost_volts);
+        }
+        scpi::Command::SetSourceVoltage(volts) => {
+            let key = settings::Key::BoostVolts;
+            let mut new = current;
+            if new.set(key, volts).is_err() {
+                errors.push(scpi::Error::DataOutOfRange);
+                return Ok(());
+            }
+            info!("Setting {}: {}", key, new);
+            config.send(new);
+            if let Err(e) = new.store(key, storage).await {
+                error!("Failed to store setting: {:?}", e);
+            }
+            return Ok(());
+        }
+        scpi::Command::SystemError => {
+            let _ = errors.write_next(&mut response);
+        }
+        scpi::Command::RandomData(_)
+            if entropy_output.stopped().is_err() || !entropy_output.stats().await.seeded =>
+        {
+            errors.push(scpi::Error::ExecutionError);
+            return Ok(());
+        }
+        scpi::Command::RandomData(n) => {
+            let _ = scpi::write_block_header(&mut response, n);
+            writer.write_all(&response).await?;
+            response.clear();
+            let mut bytes = [0u8; 64];
+            let mut remaining = n as usize;
+            while remaining > 0 {
+                let chunk = &mut bytes[..remaining.min(64)];
+                if entropy_output.read_drbg(chunk, false).await.is_err() {
+                    // The block is cut short, the host times out on it.
+                    errors.push(scpi::Error::ExecutionError);
+                    return Ok(());
                 }
+                writer.write_all(chunk).await?;
+                remaining -= chunk.len();
             }
         }
     }
+    let _ = response.push(b'\n');
+    writer.write_all(&response).await
 }
@@ -0,0 +1,108 @@
+use crate::{settings, usb::MAX_LABEL_LEN};
+
+use super::Mode;
+
+/// Largest `random` request, in bytes.
+pub(super) const MAX_RANDOM_BYTES: usize = 1024;
+
+pub(super) const HELP: &str = "\
+help                      show this help\r\n\
+status                    show dose, entropy, DRBG and UART bridge state\r\n\
+config get [key]          show one or all settings\r\n\
+config set <key> <value>  change and store a setting\r\n\
+random <n>                print n random bytes in hex, up to 1024\r\n\
+reset-count               restart the pulse count from zero\r\n\
+label                     show the serial number and label\r\n\
+label set <name>          store a label, shown in the USB product name\r\n\
+label clear               remove the label\r\n\
+reboot                    restart the device\r\n\
+mode <mode>               telemetry, frames, json, raw, random,\r\n\
+                          true-random, gq or scpi\r\n";
+
+pub(super) enum Command<'a> {
+    Help,
+    Status,
+    ConfigGet(Option<settings::Key>),
+    ConfigSet(settings::Key, &'a str),
+    Random(usize),
+    ResetCount,
+    LabelGet,
+    LabelSet(&'a str),
+    LabelClear,
+    Reboot,
+    Mode(Mode),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub(super) enum Error {
+    Unknown,
+    /// Wrong arguments, holds the expected usage.
+    Usage(&'static str),
+    UnknownKey,
+}
+
+impl Error {
+    pub(super) fn message(self) -> &'static str {
+        match self {
+            Error::Unknown => "unknown command, try help",
+            Error::Usage(usage) => usage,
+            Error::UnknownKey => "unknown key, see config get",
+        }
+    }
+}
+
+pub(super) fn parse(line: &str) -> Result<Command<'_>, Error> {
+    let mut words = line.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        return Err(Error::Unknown);
+    };
+    let (usage, command) = match name {
+        "help" => ("help", Some(Command::Help)),
+        "status" => ("status", Some(Command::Status)),
+        "config" => {
+            let usage = "config get [key] | config set <key> <value>";
+            let key = |word: Option<&str>| word.map(settings::Key::from_name);
+            match (words.next(), key(words.next()), words.next()) {
+                (Some("get"), None, None) => (usage, Some(Command::ConfigGet(None))),
+                (Some("get"), Some(Some(key)), None) => {
+                    (usage, Some(Command::ConfigGet(Some(key))))
+                }
+                (Some("set"), Some(Some(key)), Some(value)) => {
+                    (usage, Some(Command::ConfigSet(key, value)))
+                }
+                (Some("get" | "set"), Some(None), _) => return Err(Error::UnknownKey),
+                _ => (usage, None),
+            }
+        }
+        "random" => (
+            "random <n>, n from 1 to 1024",
+            words
+                .next()
+                .and_then(|n| n.parse().ok())
+                .filter(|n| (1..=MAX_RANDOM_BYTES).contains(n))
+                .map(Command::Random),
+        ),
+        "reset-count" => ("reset-count", Some(Command::ResetCount)),
+        "label" => (
+            "label [set <name> | clear], name up to 24 characters",
+            match (words.next(), words.next()) {
+                (None, _) => Some(Command::LabelGet),
+                (Some("set"), Some(name)) if name.len() <= MAX_LABEL_LEN => {
+                    Some(Command::LabelSet(name))
+                }
+                (Some("clear"), None) => Some(Command::LabelClear),
+                _ => None,
+            },
+        ),
+        "reboot" => ("reboot", Some(Command::Reboot)),
+        "mode" => (
+            "mode telemetry|frames|json|raw|random|true-random|gq|scpi",
+            words.next().and_then(Mode::from_name).map(Command::Mode),
+        ),
+        _ => return Err(Error::Unknown),
+    };
+    match command {
+        Some(command) if words.next().is_none() => Ok(command),
+        _ => Err(Error::Usage(usage)),
+    }
+}
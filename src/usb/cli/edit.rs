@@ -0,0 +1,83 @@
+/// Longest command line, a longer one is rejected when it ends.
+const MAX_LINE: usize = 64;
+
+/// A line that did not fit [`MAX_LINE`].
+pub(super) struct TooLong;
+
+impl TooLong {
+    pub(super) const MESSAGE: &'static [u8] = b"line too long, up to 64 characters\r\n";
+}
+
+/// Minimal line editing for a serial terminal.
+///
+/// Supports backspace (BS or DEL) and Ctrl-C to drop the line. CR, LF and
+/// CR LF all end a line.
+pub(super) struct LineEditor {
+    line: heapless::Vec<u8, MAX_LINE>,
+    /// Characters were dropped from the current line.
+    overflow: bool,
+    last_cr: bool,
+}
+
+impl LineEditor {
+    pub(super) const fn new() -> Self {
+        Self {
+            line: heapless::Vec::new(),
+            overflow: false,
+            last_cr: false,
+        }
+    }
+
+    /// Drops the line typed so far.
+    pub(super) fn clear(&mut self) {
+        self.line.clear();
+        self.overflow = false;
+    }
+
+    /// The line typed so far.
+    pub(super) fn pending(&self) -> &[u8] {
+        &self.line
+    }
+
+    /// Feeds one received byte, appending what the terminal should show to
+    /// `echo`. Returns the line once it is complete, or [`TooLong`] if
+    /// characters had to be dropped from it.
+    pub(super) fn push<const N: usize>(
+        &mut self,
+        byte: u8,
+        echo: &mut heapless::Vec<u8, N>,
+    ) -> Option<Result<heapless::Vec<u8, MAX_LINE>, TooLong>> {
+        let last_cr = core::mem::replace(&mut self.last_cr, byte == b'\r');
+        match byte {
+            b'\n' if last_cr => None,
+            b'\r' | b'\n' => {
+                let _ = echo.extend_from_slice(b"\r\n");
+                let line = core::mem::take(&mut self.line);
+                if core::mem::take(&mut self.overflow) {
+                    return Some(Err(TooLong));
+                }
+                Some(Ok(line))
+            }
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    let _ = echo.extend_from_slice(b"\x08 \x08");
+                }
+                None
+            }
+            0x03 => {
+                self.clear();
+                let _ = echo.extend_from_slice(b"^C\r\n");
+                None
+            }
+            0x20..=0x7e => {
+                if self.line.push(byte).is_ok() {
+                    let _ = echo.push(byte);
+                } else {
+                    self.overflow = true;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+}
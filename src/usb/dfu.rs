@@ -0,0 +1,105 @@
+//! DFU runtime interface, lets `dfu-util` restart the device into the
+//! bootloader for a firmware update.
+//!
+//! DETACH leaves a request for the bootloader in the reserved RAM word and
+//! resets, the bootloader then enumerates in DFU mode.
+
+use banana_boot::dfu::{self, request, State, Status};
+use embassy_stm32::usb::{Driver, Instance};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use embassy_time::Timer;
+use embassy_usb::{
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
+    msos, Builder, Handler,
+};
+
+const DEVICE_INTERFACE_GUIDS: &[&str] = &["{a3c1f4d2-8e57-4b19-b0d6-7f2e9c4a61e8}"];
+
+static DETACH: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// Answers the DFU requests of the runtime interface.
+pub(super) struct Runtime {
+    interface: u16,
+}
+
+impl Runtime {
+    pub(super) fn new() -> Self {
+        Self { interface: 0 }
+    }
+
+    fn accepts(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == self.interface
+    }
+}
+
+impl Handler for Runtime {
+    fn control_out(&mut self, req: Request, _data: &[u8]) -> Option<OutResponse> {
+        if !self.accepts(&req) {
+            return None;
+        }
+        match req.request {
+            request::DETACH => {
+                defmt::info!("DFU detach requested");
+                DETACH.signal(());
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.accepts(&req) {
+            return None;
+        }
+        let (status, state);
+        let reply: &[u8] = match req.request {
+            request::GETSTATUS => {
+                status = dfu::status_reply(Status::Ok, State::AppIdle);
+                &status
+            }
+            request::GETSTATE => {
+                state = [State::AppIdle as u8];
+                &state
+            }
+            _ => return Some(InResponse::Rejected),
+        };
+        let len = reply.len().min(req.length as usize).min(buf.len());
+        buf[..len].copy_from_slice(&reply[..len]);
+        Some(InResponse::Accepted(&buf[..len]))
+    }
+}
+
+/// Adds the DFU runtime interface.
+pub(super) fn new<'d, T: Instance>(
+    builder: &mut Builder<'d, Driver<'d, T>>,
+    runtime: &'d mut Runtime,
+) {
+    {
+        let mut function = builder.function(dfu::CLASS, dfu::SUBCLASS, dfu::PROTOCOL_RUNTIME);
+        function.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+        function.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+            "DeviceInterfaceGUIDs",
+            msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
+        ));
+        let mut interface = function.interface();
+        runtime.interface = u8::from(interface.interface_number()) as u16;
+        let mut alt = interface.alt_setting(dfu::CLASS, dfu::SUBCLASS, dfu::PROTOCOL_RUNTIME, None);
+        alt.descriptor(dfu::DESCRIPTOR_FUNCTIONAL, &dfu::FUNCTIONAL_DESCRIPTOR);
+    }
+    builder.handler(runtime);
+}
+
+/// Resets into the bootloader after a DETACH request.
+pub(super) async fn detach() {
+    DETACH.wait().await;
+    // Let the status stage of the request complete first.
+    Timer::after_millis(50).await;
+    // SAFETY: the word is reserved in memory.x, outside the memory of the
+    // program.
+    unsafe {
+        (banana_boot::DFU_REQUEST_ADDRESS as *mut u32).write_volatile(banana_boot::DFU_REQUEST)
+    };
+    cortex_m::peripheral::SCB::sys_reset();
+}
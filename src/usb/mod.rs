@@ -1,31 +1,99 @@
+#[cfg(not(feature = "chaoskey"))]
+mod acm;
+#[cfg(not(feature = "chaoskey"))]
 mod cli;
+mod dfu;
+#[cfg(not(feature = "chaoskey"))]
 mod uart;
+mod vendor;
+#[cfg(not(feature = "chaoskey"))]
+mod writer;
 
+#[cfg(not(feature = "chaoskey"))]
+pub(crate) use uart::ModemPins;
+
+use core::fmt::Write;
+
+#[cfg(feature = "chaoskey")]
 use embassy_futures::join::join3;
+#[cfg(not(feature = "chaoskey"))]
+use embassy_futures::join::join5;
 use embassy_stm32::{
     gpio::{Level, Output, Speed},
-    mode::Async,
     peripherals::{PA11, PA12, USB},
-    usart::Uart,
     usb::Driver,
     Peri,
 };
-use embassy_sync::pubsub::DynSubscriber;
+#[cfg(not(feature = "chaoskey"))]
+use embassy_stm32::{mode::Async, usart::Uart};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex, watch::DynReceiver};
+#[cfg(not(feature = "chaoskey"))]
+use embassy_sync::{pubsub::DynSubscriber, watch::DynSender};
 use embassy_time::Timer;
-use embassy_usb::{
-    class::cdc_acm::{CdcAcmClass, State},
-    Builder,
-};
+#[cfg(not(feature = "chaoskey"))]
+use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
+use embassy_usb::{msos, Builder};
+use sequential_storage::cache::NoCache;
+
+use crate::{entropy, storage, Irqs};
+#[cfg(not(feature = "chaoskey"))]
+use crate::{geiger, settings::Settings};
+
+type Storage = Mutex<ThreadModeRawMutex, storage::Storage<NoCache, 32>>;
+
+/// Vendor and product ID, the ChaosKey build takes the ChaosKey's.
+#[cfg(not(feature = "chaoskey"))]
+const USB_ID: (u16, u16) = (0xc0de, 0xcafe);
 
-use crate::{geiger, Irqs};
+const PRODUCT: &str = "Banana RNG";
+
+/// Longest user label, it has to fit the storage buffer next to its key.
+const MAX_LABEL_LEN: usize = 24;
+
+/// The 96-bit factory UID in hex, unique to each device.
+fn serial_number() -> heapless::String<24> {
+    let mut serial = heapless::String::new();
+    for byte in embassy_stm32::uid::uid() {
+        let _ = core::write!(&mut serial, "{byte:02X}");
+    }
+    serial
+}
+
+/// What the two serial ports need.
+#[cfg(not(feature = "chaoskey"))]
+pub(crate) struct Serial {
+    pub(crate) uart: Uart<'static, Async>,
+    pub(crate) modem: ModemPins,
+    pub(crate) geiger_subscriber: DynSubscriber<'static, geiger::count::Message>,
+    pub(crate) entropy_status: DynReceiver<'static, entropy::Status>,
+    pub(crate) settings: DynSender<'static, Settings>,
+}
+
+/// The serial ports are left out of the ChaosKey build.
+#[cfg(feature = "chaoskey")]
+pub(crate) struct Serial;
+
+/// Reads the user label, empty if none is set.
+async fn read_label(storage: &Storage) -> heapless::String<MAX_LABEL_LEN> {
+    let mut label = heapless::String::new();
+    let mut storage = storage.lock().await;
+    if let Ok(Some(bytes)) = storage.read::<_, &[u8]>(storage::keys::LABEL).await {
+        if let Ok(text) = core::str::from_utf8(bytes) {
+            let _ = label.push_str(text);
+        }
+    }
+    label
+}
 
 #[embassy_executor::task]
 pub(crate) async fn run(
     pusb: Peri<'static, USB>,
     pa11: Peri<'static, PA11>,
     mut pa12: Peri<'static, PA12>,
-    uart: Uart<'static, Async>,
-    geiger_subscriber: DynSubscriber<'static, geiger::count::Message>,
+    serial: Serial,
+    vendor_status: DynReceiver<'static, entropy::Status>,
+    entropy_output: &'static entropy::Output,
+    storage: &'static Storage,
 ) {
     {
         // Reset USB for development only
@@ -34,33 +102,93 @@ pub(crate) async fn run(
     }
 
     let driver = Driver::new(pusb, Irqs, pa12, pa11);
-    let mut config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    #[cfg(not(feature = "chaoskey"))]
+    let mut config = embassy_usb::Config::new(USB_ID.0, USB_ID.1);
+    // The IDs of the Altus Metrum ChaosKey, so the Linux `chaoskey` driver
+    // binds to the vendor interface and feeds the kernel entropy pool.
+    #[cfg(feature = "chaoskey")]
+    let mut config = banana_chaoskey::config();
     config.manufacturer = Some("Tnze");
-    config.product = Some("Banana RNG");
-    config.serial_number = Some("TNZ1");
+    // The label tells devices apart by name, e.g. in udev rules.
+    let label = read_label(storage).await;
+    let mut product = heapless::String::<{ PRODUCT.len() + 3 + MAX_LABEL_LEN }>::new();
+    let _ = if label.is_empty() {
+        core::write!(&mut product, "{PRODUCT}")
+    } else {
+        core::write!(&mut product, "{PRODUCT} ({label})")
+    };
+    let serial_number = serial_number();
+    config.product = Some(&product);
+    config.serial_number = Some(&serial_number);
 
     let mut config_descriptor = [0; 256];
     let mut bos_descriptor = [0; 256];
+    // WinUSB descriptors for the vendor and DFU interfaces.
+    let mut msos_descriptor = [0; 512];
     let mut control_buf = [0; 32];
 
+    #[cfg(not(feature = "chaoskey"))]
     let mut cli_state = State::new();
-    let mut uart_state = State::new();
+    #[cfg(not(feature = "chaoskey"))]
+    let mut uart_state = acm::State::new(uart::supported);
+    let mut vendor_control = vendor::Control::new(vendor_status);
+    let mut dfu_runtime = dfu::Runtime::new();
 
     let mut builder = Builder::new(
         driver,
         config,
         &mut config_descriptor,
         &mut bos_descriptor,
-        &mut [], // no msos descriptors
+        &mut msos_descriptor,
         &mut control_buf,
     );
+    // Lets Windows bind WinUSB to the vendor and DFU interfaces without an
+    // INF file.
+    builder.msos_descriptor(msos::windows_version::WIN8_1, 0x20);
 
-    let mut cli_class = CdcAcmClass::new(&mut builder, &mut cli_state, 64);
-    let uart_class = CdcAcmClass::new(&mut builder, &mut uart_state, 64);
-    let mut usb = builder.build();
-    let usb_fut = usb.run();
-    let cli_fut = cli::transfer(&mut cli_class, geiger_subscriber);
-    let uart_fut = uart::uart_transfer(uart_class, uart);
+    #[cfg(not(feature = "chaoskey"))]
+    {
+        let Serial {
+            uart,
+            modem,
+            geiger_subscriber,
+            entropy_status,
+            settings,
+        } = serial;
+        let cli_class = CdcAcmClass::new(&mut builder, &mut cli_state, 64);
+        let uart_class = acm::AcmClass::new(&mut builder, &mut uart_state, 64);
+        let vendor_endpoint = vendor::new(&mut builder, &mut vendor_control);
+        dfu::new(&mut builder, &mut dfu_runtime);
+        let mut usb = builder.build();
+        let usb_fut = usb.run();
+        let cli_fut = cli::transfer(
+            cli_class,
+            geiger_subscriber,
+            entropy_status,
+            entropy_output,
+            settings,
+            storage,
+        );
+        let uart_fut = uart::uart_transfer(uart_class, uart, modem);
+        let vendor_fut = vendor::transfer(vendor_endpoint, entropy_output);
+
+        join5(usb_fut, cli_fut, uart_fut, vendor_fut, dfu::detach()).await;
+    }
 
-    join3(usb_fut, cli_fut, uart_fut).await;
+    #[cfg(feature = "chaoskey")]
+    {
+        let Serial = serial;
+        // The chaoskey driver matches the whole device and takes the first
+        // bulk IN endpoint of any interface it probes, which would include
+        // the CDC-ACM data interfaces.
+        let vendor_endpoint = vendor::new(&mut builder, &mut vendor_control);
+        dfu::new(&mut builder, &mut dfu_runtime);
+        let mut usb = builder.build();
+        join3(
+            usb.run(),
+            vendor::transfer(vendor_endpoint, entropy_output),
+            dfu::detach(),
+        )
+        .await;
+    }
 }
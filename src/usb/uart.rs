@@ -1,78 +1,466 @@
+mod nine_bit;
+
+use core::{
+    cell::{Cell, RefCell},
+    ops::RangeInclusive,
+};
+
+use banana_queue::Queue;
+
 use defmt::*;
-use embassy_futures::{join::join, select::select};
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_stm32::{
+    gpio::{Input, Level, Output, Pull, Speed},
     mode::Async,
-    usart::{Config, DataBits, Parity, StopBits, Uart},
+    peripherals::{PA8, PB12, PB13, PB14, PB15, PB5},
+    usart::{self, Config, DataBits, Parity, StopBits, Uart, UartTx},
     usb::{Driver, Instance},
+    Peri,
 };
-use embassy_usb::class::cdc_acm::{
-    CdcAcmClass, LineCoding, ParityType as ParityTypeACM, StopBits as StopBitsACM,
+use embassy_sync::{
+    blocking_mutex::{
+        raw::{NoopRawMutex, ThreadModeRawMutex},
+        Mutex,
+    },
+    signal::Signal,
 };
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use embassy_usb::class::cdc_acm::{ParityType as ParityTypeACM, StopBits as StopBitsACM};
+use static_cell::StaticCell;
+
+use super::acm::{self, AcmClass, LineCoding, SerialState};
+
+/// Baud rates the USART divider reaches, at 16 times oversampling.
+#[cfg(not(feature = "uart3_cdc"))]
+const BAUD_RATES: RangeInclusive<u32> = 1200..=72_000_000 / 16;
+/// USART3 runs from the 36 MHz APB1.
+#[cfg(feature = "uart3_cdc")]
+const BAUD_RATES: RangeInclusive<u32> = 1200..=36_000_000 / 16;
+
+/// Bytes queued in each direction, 100 ms at 115200 baud with room to
+/// spare.
+const QUEUE_LEN: usize = 1280;
+/// DMA ring buffer of the receiver, emptied into the queue as it fills.
+const RING_LEN: usize = 256;
+static RING: StaticCell<[u8; RING_LEN]> = StaticCell::new();
+
+/// How often the modem input lines are sampled.
+const MODEM_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Bytes written to the UART between CTS checks, at most this many follow
+/// the receiver deasserting CTS.
+const CTS_CHUNK: usize = 16;
+
+/// Modem control lines of the bridge. They are active low, like the TTL side
+/// of an RS-232 transceiver and USB-UART chips, so the usual auto-reset
+/// circuits of Arduino and ESP boards work.
+///
+/// DTR and RTS follow the host. CTS gates the data sent to the UART, it reads
+/// as asserted when nothing is connected. DCD, DSR and RI are reported to the
+/// host. CDC has no CTS notification.
+pub(crate) struct ModemPins {
+    pub(crate) dtr: Peri<'static, PB12>,
+    pub(crate) rts: Peri<'static, PB13>,
+    pub(crate) cts: Peri<'static, PB14>,
+    pub(crate) dsr: Peri<'static, PB15>,
+    pub(crate) dcd: Peri<'static, PA8>,
+    pub(crate) ri: Peri<'static, PB5>,
+}
+
+/// A [`Queue`] with a task on either side.
+struct Pipe<const N: usize> {
+    queue: RefCell<Queue<N>>,
+    readable: Signal<NoopRawMutex, ()>,
+    writable: Signal<NoopRawMutex, ()>,
+}
+
+impl<const N: usize> Pipe<N> {
+    fn new() -> Self {
+        Self {
+            queue: RefCell::new(Queue::new()),
+            readable: Signal::new(),
+            writable: Signal::new(),
+        }
+    }
+
+    fn clear(&self) {
+        self.queue.borrow_mut().clear();
+    }
+
+    /// Waits for bytes and moves as many as fit into `out`.
+    async fn read(&self, out: &mut [u8]) -> usize {
+        loop {
+            let n = self.queue.borrow_mut().read(out);
+            if n > 0 {
+                self.writable.signal(());
+                return n;
+            }
+            self.readable.wait().await;
+        }
+    }
+
+    /// Queues all of `data`, waiting for room as needed.
+    async fn write_all(&self, mut data: &[u8]) {
+        loop {
+            let n = self.queue.borrow_mut().write(data);
+            if n > 0 {
+                self.readable.signal(());
+            }
+            data = &data[n..];
+            if data.is_empty() {
+                return;
+            }
+            self.writable.wait().await;
+        }
+    }
+
+    /// Queues what fits of `data` and returns the number of bytes dropped.
+    fn write_lossy(&self, data: &[u8]) -> usize {
+        let n = self.queue.borrow_mut().write(data);
+        if n > 0 {
+            self.readable.signal(());
+        }
+        data.len() - n
+    }
+}
+
+/// A control line output level, low when asserted.
+fn asserted(asserted: bool) -> Level {
+    if asserted {
+        Level::Low
+    } else {
+        Level::High
+    }
+}
+
+/// Bridge errors since boot.
+#[derive(Clone, Copy)]
+pub(super) struct Errors {
+    pub(super) framing: u32,
+    pub(super) parity: u32,
+    pub(super) overrun: u32,
+    pub(super) noise: u32,
+    /// Bytes from the UART dropped because the host did not read them.
+    pub(super) overflow: u32,
+    /// Line codings the UART cannot do, refused to the host.
+    pub(super) line_codings: u32,
+}
+
+static ERRORS: Mutex<ThreadModeRawMutex, Cell<Errors>> = Mutex::new(Cell::new(Errors {
+    framing: 0,
+    parity: 0,
+    overrun: 0,
+    noise: 0,
+    overflow: 0,
+    line_codings: 0,
+}));
+
+pub(super) fn errors() -> Errors {
+    ERRORS.lock(Cell::get)
+}
+
+fn count(f: impl FnOnce(&mut Errors)) {
+    ERRORS.lock(|errors| {
+        let mut counts = errors.get();
+        f(&mut counts);
+        errors.set(counts);
+    });
+}
+
+/// Whether the UART can take `line_coding`, the check of SET_LINE_CODING.
+pub(super) fn supported(line_coding: &LineCoding) -> bool {
+    let supported = line_coding_to_uart_config(line_coding).is_some();
+    if !supported {
+        warn!("Unsupported line coding: {:?}", line_coding);
+        count(|errors| errors.line_codings += 1);
+    }
+    supported
+}
 
 pub(super) async fn uart_transfer<'d, T: Instance + 'd>(
-    class: CdcAcmClass<'d, Driver<'d, T>>,
-    mut uart: Uart<'static, Async>,
+    class: AcmClass<'d, Driver<'d, T>>,
+    uart: Uart<'static, Async>,
+    modem: ModemPins,
 ) {
     info!("Uart transfer is running");
-    let (mut sender, mut receiver, control) = class.split_with_control();
-    let (tx, rx) = uart.split_ref();
+    let (mut sender, mut receiver, mut notifier, shared) = class.split();
+    let (mut tx, rx) = uart.split();
+    let mut rx = rx.into_ring_buffered(RING.init([0; RING_LEN]));
+    let to_uart = Pipe::<QUEUE_LEN>::new();
+    let to_host = Pipe::<QUEUE_LEN>::new();
+    let mut dtr = Output::new(modem.dtr, Level::High, Speed::Low);
+    let mut rts = Output::new(modem.rts, Level::High, Speed::Low);
+    let cts = Input::new(modem.cts, Pull::Down);
+    let dsr = Input::new(modem.dsr, Pull::Up);
+    let dcd = Input::new(modem.dcd, Pull::Up);
+    let ri = Input::new(modem.ri, Pull::Up);
+    // The last serial state the host has seen.
+    let reported = Cell::new(None);
+    // Receive errors not reported to the host yet.
+    let receive_errors = Cell::new(SerialState::default());
     loop {
         sender.wait_connection().await;
         info!("CDC-ACM connection detected");
 
-        let line_coding = sender.line_coding();
+        let line_coding = shared.line_coding();
         info!("CDC-ACM line coding config: {:?}", line_coding);
-        let config = line_coding_to_uart_config(&line_coding);
-        tx.set_config(&config).unwrap();
-        rx.set_config(&config).unwrap();
-        select(
+        // Only supported line codings are accepted from the host.
+        let config = line_coding_to_uart_config(&line_coding).unwrap_or_default();
+        if let Err(err) = tx.set_config(&config).and_then(|()| rx.set_config(&config)) {
+            warn!("Failed to configure the UART: {:?}", err);
+            count(|errors| errors.line_codings += 1);
+            while shared.line_coding() == line_coding {
+                shared.changed().await;
+            }
+            continue;
+        }
+        to_uart.clear();
+        to_host.clear();
+        let stick_parity = stick_parity(&line_coding);
+        let nine_bit = line_coding.data_bits == 8 && stick_parity.is_some();
+        let _nine_bit_session = nine_bit.then(nine_bit::Session::begin);
+        let session = select4(
             async {
-                control.control_changed().await;
-                info!("Control changed");
+                loop {
+                    dtr.set_level(asserted(shared.dtr()));
+                    rts.set_level(asserted(shared.rts()));
+                    shared.changed().await;
+                    if shared.line_coding() != line_coding {
+                        info!("Line coding changed");
+                        break;
+                    }
+                    if shared.dtr() {
+                        // The port may have been reopened, tell the new
+                        // reader the modem state.
+                        reported.set(None);
+                    }
+                }
             },
-            join(
+            select(
                 async {
-                    let mut buffer = [0u8; 64];
+                    let mut packet = [0u8; 64];
+                    loop {
+                        match receiver.read_packet(&mut packet).await {
+                            Ok(n) => to_uart.write_all(&packet[..n]).await,
+                            Err(err) => {
+                                info!("Read from CDC-ACM error: {:?}", err);
+                                break;
+                            }
+                        }
+                    }
+                },
+                async {
+                    let mut chunk = [0u8; CTS_CHUNK];
                     loop {
-                        match receiver.read_packet(&mut buffer).await {
-                            Ok(n) => tx.write(&buffer[..n]).await.unwrap(),
-                            Err(err) => error!("Read from CDC-ACM error: {:?}", err),
+                        // Queued bytes go out before the break.
+                        match select(to_uart.read(&mut chunk), shared.send_break()).await {
+                            Either::First(n) => {
+                                while cts.is_high() {
+                                    Timer::after_millis(1).await;
+                                }
+                                let chunk = &mut chunk[..n];
+                                let result = match stick_parity {
+                                    Some(mark) if nine_bit => {
+                                        nine_bit::write(chunk, mark).await;
+                                        Ok(())
+                                    }
+                                    Some(mark) => {
+                                        add_stick_parity(chunk, mark);
+                                        tx.write(chunk).await
+                                    }
+                                    None => tx.write(chunk).await,
+                                };
+                                if let Err(err) = result {
+                                    warn!("Write to USART error: {:?}", err);
+                                }
+                            }
+                            Either::Second(ms) => {
+                                send_break(&mut tx, shared, ms, line_coding.data_rate).await
+                            }
                         }
                     }
                 },
+            ),
+            select(
                 async {
                     let mut buffer = [0u8; 64];
                     loop {
-                        match rx.read_until_idle(&mut buffer).await {
-                            Ok(n) => sender.write_packet(&buffer[..n]).await.unwrap(),
-                            Err(err) => error!("Read from USART error: {:?}", err),
+                        // The ring buffer DMA only starts on the first read,
+                        // it must not take the 9-bit words.
+                        let result = match stick_parity {
+                            Some(mark) if nine_bit => nine_bit::read(&mut buffer, mark).await,
+                            Some(mark) => rx
+                                .read(&mut buffer)
+                                .await
+                                .map(|n| (n, strip_stick_parity(&mut buffer[..n], mark))),
+                            None => rx.read(&mut buffer).await.map(|n| (n, 0)),
+                        };
+                        match result {
+                            Ok((n, parity_errors)) => {
+                                if parity_errors > 0 {
+                                    count(|errors| errors.parity += parity_errors as u32);
+                                    receive_errors.set(receive_errors.get() | SerialState::PARITY);
+                                }
+                                let dropped = to_host.write_lossy(&buffer[..n]);
+                                if dropped > 0 {
+                                    count(|errors| errors.overflow += dropped as u32);
+                                    receive_errors.set(receive_errors.get() | SerialState::OVERRUN);
+                                }
+                            }
+                            Err(err) => receive_error(err, &receive_errors),
+                        }
+                    }
+                },
+                async {
+                    let mut packet = [0u8; 64];
+                    loop {
+                        let n = to_host.read(&mut packet).await;
+                        if let Err(err) = sender.write_packet(&packet[..n]).await {
+                            info!("Write to CDC-ACM error: {:?}", err);
+                            break;
                         }
                     }
                 },
             ),
+            async {
+                let mut ticker = Ticker::every(MODEM_POLL_INTERVAL);
+                loop {
+                    // The error bits are events, sent once.
+                    let mut state = receive_errors.take();
+                    state.set(SerialState::DCD, dcd.is_low());
+                    state.set(SerialState::DSR, dsr.is_low());
+                    state.set(SerialState::RING, ri.is_low());
+                    if reported.get() != Some(state) {
+                        if let Err(err) = notifier.serial_state(state).await {
+                            info!("Notify CDC-ACM error: {:?}", err);
+                            break;
+                        }
+                        reported.set(Some(state));
+                    }
+                    ticker.next().await;
+                }
+            },
         )
         .await;
+        // Everything but a new line coding means the host is gone.
+        if !matches!(session, Either4::First(())) {
+            dtr.set_high();
+            rts.set_high();
+        }
     }
 }
 
-fn line_coding_to_uart_config(line_coding: &LineCoding) -> Config {
+/// Counts a UART receive error and queues it for the host.
+fn receive_error(err: usart::Error, receive_errors: &Cell<SerialState>) {
+    warn!("Read from USART error: {:?}", err);
+    let flag = match err {
+        usart::Error::Framing => {
+            count(|errors| errors.framing += 1);
+            SerialState::FRAMING
+        }
+        usart::Error::Parity => {
+            count(|errors| errors.parity += 1);
+            SerialState::PARITY
+        }
+        // Also the DMA ring buffer filling up.
+        usart::Error::Overrun => {
+            count(|errors| errors.overrun += 1);
+            SerialState::OVERRUN
+        }
+        usart::Error::Noise => {
+            count(|errors| errors.noise += 1);
+            return;
+        }
+        _ => return,
+    };
+    receive_errors.set(receive_errors.get() | flag);
+}
+
+/// Holds a break for `ms` milliseconds, or until the host ends it for
+/// [`acm::BREAK_UNTIL_CLEARED`]. The USART only sends single break
+/// characters, so they are repeated back to back.
+async fn send_break(
+    tx: &mut UartTx<'static, Async>,
+    shared: &acm::Shared,
+    mut ms: u16,
+    baudrate: u32,
+) {
+    // A break character is at most 13 bit times with the stop bits.
+    let character = Duration::from_micros(13_000_000 / baudrate.max(1) as u64);
+    let mut end = Instant::now() + Duration::from_millis(ms.into());
+    while ms != 0 && (ms == acm::BREAK_UNTIL_CLEARED || Instant::now() < end) {
+        tx.send_break();
+        let next = select(Timer::after(character), shared.send_break()).await;
+        if let Either::Second(next) = next {
+            ms = next;
+            end = Instant::now() + Duration::from_millis(ms.into());
+        }
+    }
+}
+
+/// The UART configuration for `line_coding`, if the UART can do it.
+fn line_coding_to_uart_config(line_coding: &LineCoding) -> Option<Config> {
+    if !BAUD_RATES.contains(&line_coding.data_rate) {
+        return None;
+    }
     let mut config = Config::default();
-    config.baudrate = line_coding.data_rate();
-    config.data_bits = match line_coding.data_bits() {
-        7 => DataBits::DataBits7,
-        8 => DataBits::DataBits8,
-        9 => DataBits::DataBits9,
-        _ => DataBits::DataBits8,
+    config.baudrate = line_coding.data_rate;
+    config.parity = match line_coding.parity_type {
+        ParityTypeACM::None => Parity::ParityNone,
+        ParityTypeACM::Odd => Parity::ParityOdd,
+        ParityTypeACM::Even => Parity::ParityEven,
+        // Sent as an extra data bit, see `stick_parity`.
+        ParityTypeACM::Mark | ParityTypeACM::Space => Parity::ParityNone,
+    };
+    // The USART has 8 and 9 bit words, parity included. There are no 5 or 6
+    // bit words. The DMA moves bytes, so 9 data bits are out of reach and the
+    // CPU moves the words of Mark and Space parity with 8 data bits.
+    config.data_bits = match (line_coding.data_bits, line_coding.parity_type) {
+        (7, ParityTypeACM::Odd | ParityTypeACM::Even) => DataBits::DataBits7,
+        (7, ParityTypeACM::Mark | ParityTypeACM::Space) => DataBits::DataBits8,
+        (8, ParityTypeACM::None | ParityTypeACM::Odd | ParityTypeACM::Even) => DataBits::DataBits8,
+        (8, ParityTypeACM::Mark | ParityTypeACM::Space)
+            if nine_bit::BAUD_RATES.contains(&line_coding.data_rate) =>
+        {
+            DataBits::DataBits9
+        }
+        _ => return None,
     };
-    config.stop_bits = match line_coding.stop_bits() {
+    config.stop_bits = match line_coding.stop_bits {
         StopBitsACM::One => StopBits::STOP1,
         StopBitsACM::OnePointFive => StopBits::STOP1P5,
         StopBitsACM::Two => StopBits::STOP2,
     };
-    config.parity = match line_coding.parity_type() {
-        ParityTypeACM::None | ParityTypeACM::Mark | ParityTypeACM::Space => Parity::ParityNone,
-        ParityTypeACM::Odd => Parity::ParityOdd,
-        ParityTypeACM::Even => Parity::ParityEven,
-    };
-    config
+    Some(config)
+}
+
+/// The level of the parity bit for Mark and Space parity, which is emulated
+/// with an extra data bit: the eighth with 7 data bits, the ninth with 8, see
+/// [`nine_bit`].
+fn stick_parity(line_coding: &LineCoding) -> Option<bool> {
+    match line_coding.parity_type {
+        ParityTypeACM::Mark => Some(true),
+        ParityTypeACM::Space => Some(false),
+        _ => None,
+    }
+}
+
+/// Puts the parity bit into 7-bit bytes for the UART.
+fn add_stick_parity(data: &mut [u8], mark: bool) {
+    for byte in data {
+        *byte = *byte & 0x7F | (mark as u8) << 7;
+    }
+}
+
+/// Checks and removes the parity bit of bytes from the UART, returns the
+/// number of parity errors.
+fn strip_stick_parity(data: &mut [u8], mark: bool) -> usize {
+    let mut errors = 0;
+    for byte in data {
+        if (*byte & 0x80 != 0) != mark {
+            errors += 1;
+        }
+        *byte &= 0x7F;
+    }
+    errors
 }
@@ -0,0 +1,115 @@
+//! Mark and Space parity with 8 data bits, in 9-bit words.
+//!
+//! The parity bit is the ninth bit of the word, which the byte-wide DMA of
+//! the driver cannot reach. For these line codings the CPU moves the words
+//! through the data register instead, and the DMA and the driver interrupts
+//! are kept off it for the session.
+
+use core::ops::RangeInclusive;
+
+use embassy_futures::yield_now;
+#[cfg(not(feature = "uart3_cdc"))]
+use embassy_stm32::pac::USART1 as USART;
+#[cfg(feature = "uart3_cdc")]
+use embassy_stm32::pac::USART3 as USART;
+use embassy_stm32::{
+    pac::usart::regs::{Cr1, Cr3},
+    usart,
+};
+
+/// Baud rates served by polling the data register, faster ones overrun the
+/// receiver while other tasks run.
+pub(super) const BAUD_RATES: RangeInclusive<u32> = 1200..=115_200;
+
+const NINTH_BIT: u16 = 1 << 8;
+
+/// Takes the data register from the DMA and the driver interrupts, and
+/// gives it back when dropped.
+pub(super) struct Session {
+    cr1: Cr1,
+    cr3: Cr3,
+}
+
+impl Session {
+    pub(super) fn begin() -> Self {
+        let cr1 = USART.cr1().read();
+        let cr3 = USART.cr3().read();
+        USART.cr3().modify(|w| {
+            w.set_dmar(false);
+            w.set_eie(false);
+        });
+        USART.cr1().modify(|w| {
+            w.set_rxneie(false);
+            w.set_peie(false);
+            w.set_idleie(false);
+        });
+        Self { cr1, cr3 }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        USART.cr1().modify(|w| {
+            w.set_rxneie(self.cr1.rxneie());
+            w.set_peie(self.cr1.peie());
+            w.set_idleie(self.cr1.idleie());
+        });
+        USART.cr3().modify(|w| {
+            w.set_dmar(self.cr3.dmar());
+            w.set_eie(self.cr3.eie());
+        });
+    }
+}
+
+/// Sends `data` with the ninth bit set for Mark parity.
+pub(super) async fn write(data: &[u8], mark: bool) {
+    for &byte in data {
+        while !USART.sr().read().txe() {
+            yield_now().await;
+        }
+        let word = u16::from(byte) | if mark { NINTH_BIT } else { 0 };
+        USART.dr().write(|w| w.set_dr(word));
+    }
+}
+
+/// Waits for a word, then reads the ones already received into `buffer`.
+/// Returns the number of bytes and how many of them had the wrong ninth bit.
+pub(super) async fn read(buffer: &mut [u8], mark: bool) -> Result<(usize, usize), usart::Error> {
+    let mut n = 0;
+    let mut parity_errors = 0;
+    while n < buffer.len() {
+        let sr = USART.sr().read();
+        let error = if sr.fe() {
+            Some(usart::Error::Framing)
+        } else if sr.ne() {
+            Some(usart::Error::Noise)
+        } else if sr.ore() {
+            Some(usart::Error::Overrun)
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            if n > 0 {
+                // Reported with the next read.
+                break;
+            }
+            // Reading the data register after the status clears the error.
+            USART.dr().read();
+            return Err(error);
+        }
+        if !sr.rxne() {
+            if n > 0 {
+                break;
+            }
+            yield_now().await;
+            continue;
+        }
+        let word = USART.dr().read().dr();
+        if (word & NINTH_BIT != 0) != mark {
+            parity_errors += 1;
+        }
+        buffer[n] = word as u8;
+        n += 1;
+    }
+    Ok((n, parity_errors))
+}
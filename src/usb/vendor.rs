@@ -0,0 +1,137 @@
+//! Vendor-specific interface for hosts that talk to the device through libusb.
+//!
+//! The interface and its bulk IN endpoint streaming DRBG output come from
+//! [`banana_chaoskey`]. Vendor control requests addressed to the interface
+//! report the state of the entropy source.
+
+use embassy_stm32::usb::{Driver, Instance};
+use embassy_sync::watch::DynReceiver;
+use embassy_usb::{
+    control::{InResponse, Recipient, Request, RequestType},
+    driver::{self, EndpointIn},
+    Builder, Handler,
+};
+
+use crate::entropy;
+
+/// The 512 bytes of packet memory already hold EP0 and two CDC-ACM functions.
+#[cfg(not(feature = "chaoskey"))]
+const MAX_PACKET_SIZE: u16 = 32;
+#[cfg(feature = "chaoskey")]
+const MAX_PACKET_SIZE: u16 = banana_chaoskey::CHAOSKEY_MAX_PACKET_SIZE;
+
+/// Returns the [`StateReport`] of the entropy source.
+const REQUEST_GET_STATE: u8 = 0x01;
+
+/// Answer to [`REQUEST_GET_STATE`], all fields little-endian.
+///
+/// | Offset | Size | Field                                                  |
+/// | ------ | ---- | ------------------------------------------------------ |
+/// | 0      | 1    | State: 0 warming up, 1 running, 2 self-test failed,    |
+/// |        |      | 3 health test failed                                   |
+/// | 1      | 1    | Failed test: 0 none, 1 known answer, 2 RCT, 3 APT,     |
+/// |        |      | 4 min-entropy too low                                  |
+/// | 2      | 2    | Startup comparison bits tested                         |
+/// | 4      | 4    | Health test failures, persisted across reboots         |
+/// | 8      | 4    | Conditioned blocks since boot                          |
+/// | 12     | 4    | Min-entropy estimate per raw bit, `f32`, NaN if none   |
+struct StateReport([u8; 16]);
+
+impl StateReport {
+    fn new(status: &entropy::Status) -> Self {
+        use entropy::{health, startup, State};
+
+        let health_failure = |failure| match failure {
+            health::Failure::RepetitionCount => 2,
+            health::Failure::AdaptiveProportion => 3,
+        };
+        let (state, failure, samples) = match status.state {
+            State::WarmingUp { samples } => (0, 0, samples),
+            State::Running => (1, 0, startup::SAMPLES),
+            State::SelfTestFailed(startup::Failure::KnownAnswer(_)) => (2, 1, 0),
+            State::SelfTestFailed(startup::Failure::LowEntropy) => (2, 4, startup::SAMPLES),
+            State::SelfTestFailed(startup::Failure::Health(failure)) => {
+                (2, health_failure(failure), 0)
+            }
+            State::HealthTestFailed(failure) => (3, health_failure(failure), startup::SAMPLES),
+        };
+        let estimate = status.estimate.map_or(f32::NAN, |estimate| estimate.min());
+
+        let mut report = [0; 16];
+        report[0] = state;
+        report[1] = failure;
+        report[2..4].copy_from_slice(&(samples.min(u16::MAX as u32) as u16).to_le_bytes());
+        report[4..8].copy_from_slice(&status.health_failures.to_le_bytes());
+        report[8..12].copy_from_slice(&status.condition.blocks.to_le_bytes());
+        report[12..16].copy_from_slice(&estimate.to_le_bytes());
+        Self(report)
+    }
+}
+
+/// Answers the vendor control requests.
+pub(super) struct Control {
+    interface: u16,
+    entropy_status: DynReceiver<'static, entropy::Status>,
+}
+
+impl Control {
+    pub(super) fn new(entropy_status: DynReceiver<'static, entropy::Status>) -> Self {
+        Self {
+            interface: 0,
+            entropy_status,
+        }
+    }
+}
+
+impl Handler for Control {
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Vendor
+            || req.recipient != Recipient::Interface
+            || req.index != self.interface
+        {
+            return None;
+        }
+        match req.request {
+            REQUEST_GET_STATE => {
+                let Some(status) = self.entropy_status.try_get() else {
+                    return Some(InResponse::Rejected);
+                };
+                let StateReport(report) = StateReport::new(&status);
+                let len = report.len().min(req.length as usize).min(buf.len());
+                buf[..len].copy_from_slice(&report[..len]);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            _ => Some(InResponse::Rejected),
+        }
+    }
+}
+
+/// Adds the vendor interface and returns its bulk IN endpoint.
+pub(super) fn new<'d, T: Instance>(
+    builder: &mut Builder<'d, Driver<'d, T>>,
+    control: &'d mut Control,
+) -> <Driver<'d, T> as driver::Driver<'d>>::EndpointIn {
+    let (interface, endpoint) = banana_chaoskey::interface(builder, MAX_PACKET_SIZE);
+    control.interface = interface.into();
+    builder.handler(control);
+    endpoint
+}
+
+/// DRBG output for the bulk IN endpoint.
+struct Drbg(&'static entropy::Output);
+
+impl banana_chaoskey::Source for Drbg {
+    type Error = entropy::output::Stopped;
+
+    async fn fill(&mut self, buf: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read_drbg(buf, false).await
+    }
+}
+
+/// Streams DRBG output for as long as the host keeps reading, the endpoint
+/// stalls once random output is stopped.
+pub(super) async fn transfer(endpoint: impl EndpointIn, entropy_output: &'static entropy::Output) {
+    let stopped = banana_chaoskey::transfer(endpoint, Drbg(entropy_output)).await;
+    defmt::warn!("Vendor endpoint stalled, {}", stopped);
+    core::future::pending().await
+}
@@ -0,0 +1,70 @@
+//! Buffered writes to the IN endpoint of a CDC-ACM class.
+
+use embassy_stm32::usb::{Driver, Instance};
+use embassy_usb::{class::cdc_acm::Sender, driver::EndpointError};
+
+/// Largest packet size of the CDC-ACM classes.
+const MAX_PACKET_SIZE: usize = 64;
+
+/// Collects writes of any length into full packets.
+///
+/// Full packets are sent as soon as they fill up, [`flush`](Self::flush) sends
+/// the rest. A transfer that ends with a full packet is terminated by a
+/// zero-length packet on flush, otherwise the host keeps waiting for more.
+pub(super) struct PacketWriter<'d, T: Instance + 'd> {
+    sender: Sender<'d, Driver<'d, T>>,
+    packet: [u8; MAX_PACKET_SIZE],
+    len: usize,
+    /// The last packet sent was full, so the transfer is not terminated yet.
+    pending_zlp: bool,
+}
+
+impl<'d, T: Instance + 'd> PacketWriter<'d, T> {
+    pub(super) fn new(sender: Sender<'d, Driver<'d, T>>) -> Self {
+        assert!(sender.max_packet_size() as usize <= MAX_PACKET_SIZE);
+        Self {
+            sender,
+            packet: [0; MAX_PACKET_SIZE],
+            len: 0,
+            pending_zlp: false,
+        }
+    }
+
+    /// Waits for the host to open the port, dropping anything left over from
+    /// the previous connection.
+    pub(super) async fn wait_connection(&mut self) {
+        self.sender.wait_connection().await;
+        self.len = 0;
+        self.pending_zlp = false;
+    }
+
+    async fn send(&mut self) -> Result<(), EndpointError> {
+        let len = core::mem::take(&mut self.len);
+        self.pending_zlp = len == self.sender.max_packet_size() as usize;
+        self.sender.write_packet(&self.packet[..len]).await
+    }
+}
+
+impl<'d, T: Instance + 'd> embedded_io_async::ErrorType for PacketWriter<'d, T> {
+    type Error = EndpointError;
+}
+
+impl<'d, T: Instance + 'd> embedded_io_async::Write for PacketWriter<'d, T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let max_packet_size = self.sender.max_packet_size() as usize;
+        let n = buf.len().min(max_packet_size - self.len);
+        self.packet[self.len..self.len + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+        if self.len == max_packet_size {
+            self.send().await?;
+        }
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        if self.len > 0 || self.pending_zlp {
+            self.send().await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+//! Prints the telemetry frames read from stdin as CSV.
+//!
+//! ```bash
+//! stty -F /dev/ttyACM0 raw -echo
+//! printf 'mode frames\n' > /dev/ttyACM0
+//! cargo run --example decode --target x86_64-unknown-linux-gnu < /dev/ttyACM0
+//! ```
+
+use std::io::{self, BufRead};
+
+use banana_telemetry::Telemetry;
+
+fn main() -> io::Result<()> {
+    let mut input = io::stdin().lock();
+    let mut frame = Vec::new();
+    println!("seq,t_ms,dur_ms,ticks,count,cpm,usvh,hv");
+    loop {
+        frame.clear();
+        if input.read_until(0, &mut frame)? == 0 {
+            return Ok(());
+        }
+        if frame.pop() != Some(0) {
+            // End of input in the middle of a frame.
+            return Ok(());
+        }
+        if frame.is_empty() {
+            continue;
+        }
+        match Telemetry::decode(&mut frame) {
+            Ok(Telemetry {
+                seq,
+                t_ms,
+                dur_ms,
+                ticks,
+                count,
+                cpm,
+                usvh,
+                hv,
+            }) => println!("{seq},{t_ms},{dur_ms},{ticks},{count},{cpm},{usvh},{hv}"),
+            Err(e) => eprintln!("bad frame: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! JSON Lines telemetry, one object per Geiger pulse.
+//!
+//! Written by hand with `core::fmt`, the fields are fixed and all numbers.
+
+use core::fmt;
+
+use crate::Telemetry;
+
+/// Long enough for every field at its longest, including the newline.
+pub const MAX_LINE_LEN: usize = 256;
+
+/// Writes `telemetry` as one line, `seq` numbers the lines so a reader can
+/// tell when some were lost.
+pub fn write_line(f: &mut impl fmt::Write, telemetry: &Telemetry) -> fmt::Result {
+    let Telemetry {
+        seq,
+        t_ms,
+        dur_ms,
+        cpm,
+        usvh,
+        hv,
+        ..
+    } = *telemetry;
+    write!(
+        f,
+        "{{\"seq\":{seq},\"t_ms\":{t_ms},\"dur_ms\":{dur_ms},\"cpm\":"
+    )?;
+    write_number(f, cpm, 2)?;
+    f.write_str(",\"usvh\":")?;
+    write_number(f, usvh, 5)?;
+    f.write_str(",\"hv\":")?;
+    write_number(f, hv, 1)?;
+    f.write_str("}\n")
+}
+
+/// JSON has no NaN or infinity, they are written as `null`. The firmware
+/// reports NaN until there are two pulses of history.
+fn write_number(f: &mut impl fmt::Write, value: f32, precision: usize) -> fmt::Result {
+    if value.is_finite() {
+        write!(f, "{value:.precision$}")
+    } else {
+        f.write_str("null")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::String;
+
+    use super::*;
+
+    const TELEMETRY: Telemetry = Telemetry {
+        seq: 41,
+        t_ms: 512_873,
+        dur_ms: 2397,
+        ticks: 172_584_000,
+        count: 123_456,
+        cpm: 25.38,
+        usvh: 0.00127,
+        hv: 379.8,
+    };
+
+    fn line(telemetry: &Telemetry) -> String {
+        let mut line = String::new();
+        write_line(&mut line, telemetry).unwrap();
+        line
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            line(&TELEMETRY),
+            "{\"seq\":41,\"t_ms\":512873,\"dur_ms\":2397,\"cpm\":25.38,\"usvh\":0.00127,\"hv\":379.8}\n"
+        );
+    }
+
+    #[test]
+    fn nan_is_null() {
+        let telemetry = Telemetry {
+            cpm: f32::NAN,
+            usvh: f32::NAN,
+            hv: f32::INFINITY,
+            ..TELEMETRY
+        };
+        assert_eq!(
+            line(&telemetry),
+            "{\"seq\":41,\"t_ms\":512873,\"dur_ms\":2397,\"cpm\":null,\"usvh\":null,\"hv\":null}\n"
+        );
+    }
+
+    #[test]
+    fn longest_line_fits() {
+        let telemetry = Telemetry {
+            seq: u32::MAX,
+            t_ms: u64::MAX,
+            dur_ms: u64::MAX,
+            ticks: u64::MAX,
+            count: u64::MAX,
+            cpm: f32::MIN,
+            usvh: f32::MIN,
+            hv: f32::MIN,
+        };
+        assert!(line(&telemetry).len() <= MAX_LINE_LEN);
+    }
+}
@@ -0,0 +1,243 @@
+//! Binary telemetry frames sent by the Banana RNG in `frames` mode.
+//!
+//! Each frame is COBS encoded and ends with a zero byte, so a reader can
+//! resynchronise at the next zero after losing bytes. Decoded, a frame is
+//!
+//! | Size     | Field                                           |
+//! | -------- | ----------------------------------------------- |
+//! | 1        | Format [`VERSION`]                              |
+//! | variable | [`Telemetry`], postcard encoded                 |
+//! | 2        | CRC-16/XMODEM of the bytes above, little-endian |
+//!
+//! The crate is `no_std` and does not allocate, the firmware encodes with it
+//! and host tools decode with it. [`json`] writes the same fields as JSON
+//! Lines.
+
+#![no_std]
+
+pub mod json;
+
+use serde::{Deserialize, Serialize};
+
+/// Format version, changed whenever [`Telemetry`] changes.
+pub const VERSION: u8 = 2;
+
+/// Upper bound of an encoded frame, including the zero delimiter.
+pub const MAX_FRAME_LEN: usize = 64;
+
+/// Decoded frame size: version, postcard varints at their longest and CRC.
+const MAX_DECODED_LEN: usize = 1 + 5 + 4 * 10 + 3 * 4 + 2;
+
+const CRC: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+
+/// One Geiger pulse.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Telemetry {
+    /// Frame number since the port was opened, a gap means lost frames.
+    pub seq: u32,
+    /// Time of the pulse since boot, in milliseconds.
+    pub t_ms: u64,
+    /// Time since the previous pulse, in milliseconds.
+    pub dur_ms: u64,
+    /// Time since the previous pulse, in 72 MHz capture timer ticks.
+    pub ticks: u64,
+    /// Number of this pulse, counted across reboots.
+    pub count: u64,
+    /// Count rate, NaN until there is enough history.
+    pub cpm: f32,
+    /// Dose rate in µSv/h, NaN until there is enough history.
+    pub usvh: f32,
+    /// Boost converter output, in volts.
+    pub hv: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer is shorter than the frame.
+    BufferFull,
+    /// Not a valid COBS frame.
+    Cobs,
+    /// The frame is shorter than its fixed fields.
+    Truncated,
+    /// The checksum does not match, the frame was corrupted.
+    Crc,
+    /// Sent by firmware using another format.
+    Version(u8),
+    /// The payload does not decode as [`Telemetry`].
+    Payload,
+}
+
+impl Telemetry {
+    /// Encodes a frame into `buf`, returning the frame including its zero
+    /// delimiter.
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a [u8], Error> {
+        let mut frame = [0; MAX_DECODED_LEN];
+        frame[0] = VERSION;
+        let payload = postcard::to_slice(self, &mut frame[1..]).map_err(|_| Error::BufferFull)?;
+        let len = 1 + payload.len();
+        let crc = CRC.checksum(&frame[..len]).to_le_bytes();
+        frame[len..len + 2].copy_from_slice(&crc);
+
+        let encoded = cobs::try_encode(&frame[..len + 2], buf).map_err(|_| Error::BufferFull)?;
+        *buf.get_mut(encoded).ok_or(Error::BufferFull)? = 0;
+        Ok(&buf[..encoded + 1])
+    }
+
+    /// Decodes one frame in place. `frame` is the bytes between two zero
+    /// delimiters, leading garbage after a resynchronisation fails the CRC.
+    pub fn decode(frame: &mut [u8]) -> Result<Self, Error> {
+        let len = cobs::decode_in_place(frame).map_err(|_| Error::Cobs)?;
+        let Some((body, crc)) = frame[..len].split_last_chunk::<2>() else {
+            return Err(Error::Truncated);
+        };
+        if CRC.checksum(body) != u16::from_le_bytes(*crc) {
+            return Err(Error::Crc);
+        }
+        let Some((&version, payload)) = body.split_first() else {
+            return Err(Error::Truncated);
+        };
+        if version != VERSION {
+            return Err(Error::Version(version));
+        }
+        postcard::from_bytes(payload).map_err(|_| Error::Payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TELEMETRY: Telemetry = Telemetry {
+        seq: 41,
+        t_ms: 512_873,
+        dur_ms: 2397,
+        ticks: 172_584_000,
+        count: 123_456,
+        cpm: 25.38,
+        usvh: 0.00127,
+        hv: 379.8,
+    };
+
+    /// Encodes `telemetry` and strips the zero delimiter.
+    fn frame(telemetry: &Telemetry) -> ([u8; MAX_FRAME_LEN], usize) {
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = telemetry.encode(&mut buf).unwrap().len();
+        assert_eq!(buf[len - 1], 0);
+        (buf, len - 1)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut buf, len) = frame(&TELEMETRY);
+        assert!(!buf[..len].contains(&0));
+        assert_eq!(Telemetry::decode(&mut buf[..len]), Ok(TELEMETRY));
+    }
+
+    #[test]
+    fn round_trip_nan() {
+        let telemetry = Telemetry {
+            cpm: f32::NAN,
+            usvh: f32::NAN,
+            ..TELEMETRY
+        };
+        let (mut buf, len) = frame(&telemetry);
+        let decoded = Telemetry::decode(&mut buf[..len]).unwrap();
+        assert!(decoded.cpm.is_nan());
+        assert!(decoded.usvh.is_nan());
+        assert_eq!(
+            Telemetry {
+                cpm: 0.,
+                usvh: 0.,
+                ..decoded
+            },
+            Telemetry {
+                cpm: 0.,
+                usvh: 0.,
+                ..TELEMETRY
+            }
+        );
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let (buf, len) = frame(&TELEMETRY);
+        // Every byte but the COBS overhead, which would change the framing.
+        for i in 1..len {
+            let mut corrupted = buf;
+            corrupted[i] ^= 0x10;
+            if corrupted[i] == 0 {
+                continue;
+            }
+            assert_eq!(
+                Telemetry::decode(&mut corrupted[..len]),
+                Err(Error::Crc),
+                "byte {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut body = [0; MAX_DECODED_LEN];
+        body[0] = VERSION + 1;
+        let payload_len = postcard::to_slice(&TELEMETRY, &mut body[1..])
+            .unwrap()
+            .len();
+        let len = 1 + payload_len;
+        let crc = CRC.checksum(&body[..len]).to_le_bytes();
+        body[len..len + 2].copy_from_slice(&crc);
+        let mut buf = [0; MAX_FRAME_LEN];
+        let encoded = cobs::encode(&body[..len + 2], &mut buf);
+        assert_eq!(
+            Telemetry::decode(&mut buf[..encoded]),
+            Err(Error::Version(VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn truncated() {
+        // Shorter than the CRC.
+        let mut buf = [0; 4];
+        let len = cobs::encode(&[VERSION], &mut buf);
+        assert_eq!(Telemetry::decode(&mut buf[..len]), Err(Error::Truncated));
+
+        // Cut short, e.g. by a reader that resynchronised.
+        let (buf, len) = frame(&TELEMETRY);
+        for cut in 1..len {
+            let mut truncated = buf;
+            assert!(
+                Telemetry::decode(&mut truncated[cut..len]).is_err(),
+                "from {cut}"
+            );
+            let mut truncated = buf;
+            assert!(
+                Telemetry::decode(&mut truncated[..cut]).is_err(),
+                "to {cut}"
+            );
+        }
+    }
+
+    #[test]
+    fn longest_frame_fits() {
+        let telemetry = Telemetry {
+            seq: u32::MAX,
+            t_ms: u64::MAX,
+            dur_ms: u64::MAX,
+            ticks: u64::MAX,
+            count: u64::MAX,
+            cpm: f32::MAX,
+            usvh: f32::MAX,
+            hv: f32::MAX,
+        };
+        let mut buf = [0; MAX_FRAME_LEN];
+        let len = telemetry.encode(&mut buf).unwrap().len();
+        assert!(len <= MAX_FRAME_LEN);
+        assert_eq!(Telemetry::decode(&mut buf[..len - 1]), Ok(telemetry));
+    }
+
+    #[test]
+    fn short_buffer() {
+        let mut buf = [0; 8];
+        assert_eq!(TELEMETRY.encode(&mut buf), Err(Error::BufferFull));
+    }
+}